@@ -1,26 +1,81 @@
 #![allow(dead_code)]
 // AGDI constant definitions
 /// AGDI Init item
-
 pub const AG_INITFEATURES: u16      = 0x0100;
 pub const AG_GETFEATURE: u16  = 0x0200;
 pub const AG_INITITEM: u16      = 0x0300;
+pub const AG_EXECITEM: u16      = 0x0400;
+pub const AG_INITBPHEAD: u16    = 0x0006; // vp: AG_Bps ** (head of Keil's breakpoint list)
 /// AGDI Init Callback
 pub const AG_INITCALLBACK: u16  = 0x0012;
 pub const AG_INITFLASHLOAD: u16 = 0x0013;
 pub const AG_STARTFLASHLOAD: u16 = 0x0014;
+/// AGDI Exec item
+pub const AG_UNINIT: u16        = 0x000C; // debug session ends
+pub const AG_RESET: u16         = 0x000D;
 
 
 pub const AG_OK: u32            = 0;
 pub const AG_ERR_GENERIC: u32   = 1;
-pub const AG_NOACCESS: u32     = 1;
+pub const AG_NOACCESS: u32     = 1;  // cannot access target (running or disconnected)
+pub const AG_RDFAILED: u32     = 2;  // memory read failed
+pub const AG_INVALOP: u32      = 3;  // invalid operation
+pub const AG_RO: u32           = 4;  // attempt to write read-only item
+pub const AG_WRFAILED: u32     = 5;  // memory write failed
+pub const AG_CANTMAP: u32      = 6;  // cannot map memory
 
 
 // Callback codes
+pub const AG_CB_TRUEXPR: u32 = 1;  // evaluate breakpoint condition (vp: AG_Bps.ep)
 pub const AG_CB_PROGRESS: u32 = 2;
+pub const AG_CB_EXECCMD: u32 = 4;  // execute command string (vp: AG_Bps.cmd)
+pub const AG_CB_MSGSTRING: u32 = 8;  // print to the command output window (vp: char *)
 pub const AG_CB_GETFLASHPARAM: u32 = 15;
 
 // Progress job codes
 pub const PROGRESS_INIT: i32 = 1;
 pub const PROGRESS_KILL: i32 = 2;
-pub const PROGRESS_SETPOS: i32 = 3;
\ No newline at end of file
+pub const PROGRESS_SETPOS: i32 = 3;
+
+// AG_GoStep codes
+pub const AG_STOPRUN: u16 = 0x01;  // force stop
+pub const AG_NSTEP: u16 = 0x02;    // execute n steps
+pub const AG_GOTILADR: u16 = 0x03; // go until address
+pub const AG_GOFORBRK: u16 = 0x04; // go until break or stop
+
+// AG_BreakFunc codes
+pub const AG_BPQUERY: u16 = 0x01;
+pub const AG_BPTOGGLE: u16 = 0x02;
+pub const AG_BPINSREM: u16 = 0x03;   // n1: 1 = insert, 0 = remove
+pub const AG_BPACTIVATE: u16 = 0x04; // n1: 1 = enable, 0 = disable
+pub const AG_BPDISALL: u16 = 0x05;   // all breakpoints disabled
+pub const AG_BPKILLALL: u16 = 0x06;  // all breakpoints killed
+
+// AG_Bps type
+pub const AG_ABREAK: u32 = 0; // execution break
+pub const AG_CBREAK: u32 = 1; // conditional break
+pub const AG_WBREAK: u32 = 2; // access break
+pub const AG_RBREAK: u32 = 3; // address-range break
+
+// AG_MemAtt codes
+pub const AG_MEMMAP: u16 = 0x01;
+pub const AG_GETMEMATT: u16 = 0x02; // pa: address and length to query
+pub const AG_SETMEMATT: u16 = 0x03;
+
+// Memory attributes
+pub const AG_ATR_EXEC: u32 = 0x01;
+pub const AG_ATR_READ: u32 = 0x02;
+pub const AG_ATR_WRITE: u32 = 0x04;
+
+// AG_GETFEATURE codes (low byte of n_code)
+pub const AG_F_MEMACCR: u16 = 0x01;  // memory access while running
+pub const AG_F_REGACCR: u16 = 0x02;  // register access while running
+pub const AG_F_TRACE: u16 = 0x03;
+pub const AG_F_COVERAGE: u16 = 0x04;
+pub const AG_F_PALYZE: u16 = 0x05;
+pub const AG_F_MEMMAP: u16 = 0x06;   // memory map available
+pub const AG_F_RESETR: u16 = 0x07;   // reset while running
+pub const AG_F_HWBREAK: u16 = 0x08;  // number of hardware breakpoints
+pub const AG_F_WATCHP: u16 = 0x09;   // number of watchpoints
+pub const AG_F_MEMACC: u16 = 0x0A;   // memory access while halted
+pub const AG_F_REGACC: u16 = 0x0B;   // register access while halted
@@ -1,16 +1,34 @@
 use crate::agdi_consts::{
-    AG_CB_GETFLASHPARAM, AG_CB_PROGRESS, AG_GETFEATURE, AG_INITCALLBACK, AG_INITFLASHLOAD,
-    AG_INITITEM, AG_NOACCESS, AG_OK, AG_STARTFLASHLOAD, PROGRESS_INIT, PROGRESS_KILL,
-    PROGRESS_SETPOS,
+    AG_ABREAK, AG_BPACTIVATE, AG_BPDISALL, AG_BPINSREM, AG_BPKILLALL, AG_BPQUERY, AG_BPTOGGLE,
+    AG_EXECITEM, AG_GETFEATURE, AG_GETMEMATT, AG_GOFORBRK, AG_GOTILADR, AG_INITBPHEAD,
+    AG_INITCALLBACK, AG_INITFEATURES, AG_INITFLASHLOAD, AG_INITITEM, AG_NOACCESS, AG_NSTEP, AG_OK,
+    AG_RESET, AG_STARTFLASHLOAD, AG_STOPRUN, AG_UNINIT, AG_WBREAK,
 };
-use crate::gdb_client::{GdbClient, TcpTransport};
+use crate::breakpoints::{
+    BreakpointKind, BreakpointManager, DEFAULT_HW_BREAKPOINTS, DEFAULT_MAX_WATCH_LEN,
+    DEFAULT_WATCHPOINTS, InstalledBreakpoint, Watchpoint,
+};
+use crate::config::{Config, ExitAction, PostDownload};
+use crate::error::{Error, OpKind, Result};
+use crate::features::{FeatureTable, TargetCaps};
+use crate::gdb_client::{DisconnectMode, GdbClient, GdbTransport, TcpTransport};
+use crate::host::{DefaultNotifier, Host, KeilHost, Notifier};
+use crate::logging;
+use crate::memory_map::{MemoryKind, MemoryMap, MemoryRegion};
+use crate::recording::RecordingTransport;
+use crate::stop_reply::{REG_PC, REG_SP, StopEvent, StopReason, StopReply};
+use crate::target_state::{TargetEvent, TargetState};
 use core::ffi::c_void;
 use core::slice;
-use std::ffi::CString;
+use std::collections::BTreeSet;
+use std::fmt;
 use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::{Mutex, OnceLock};
-use user32::MessageBoxA;
-use winapi::winuser::{MB_ICONINFORMATION, MB_OK};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
 
 #[repr(C, packed(1))]
 #[derive(Default)]
@@ -27,6 +45,7 @@ unsafe impl Send for PgRess {}
 unsafe impl Sync for PgRess {}
 
 #[repr(C, packed)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct GADR {
     pub adr: u32,
     pub err_adr: u32,
@@ -66,6 +85,38 @@ pub struct AG_Bps {
     pub opc: [u8; 8],               // Opcode-Save Area for Monitors
 }
 
+impl AG_Bps {
+    pub fn bp_type(&self) -> u32 {
+        self.type_enabled_flags & 0x0F
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.type_enabled_flags & (1 << 4) != 0
+    }
+
+    /// many 已经是字节数
+    pub fn byte_object(&self) -> bool {
+        self.type_enabled_flags & (1 << 6) != 0
+    }
+
+    /// 观察点覆盖的字节数：tsize 为单个对象的大小，many 为对象个数
+    pub fn watch_len(&self) -> Result<u32> {
+        let (tsize, many) = (self.tsize, self.many);
+        if self.byte_object() || tsize == 0 {
+            return Ok(many);
+        }
+        tsize.checked_mul(many).ok_or_else(|| Error::Breakpoint {
+            addr: self.adr,
+            reason: format!("watch range of {} x {} bytes is too large", many, tsize),
+        })
+    }
+}
+
+/// Keil 断点链表头指针的地址（AG_INITBPHEAD）
+struct BpHead(*mut *mut AG_Bps);
+
+unsafe impl Send for BpHead {}
+
 #[repr(C)]
 pub union GVAL {
     pub u32: u32,       // 32-Bit unsigned int
@@ -108,61 +159,173 @@ pub struct FlashParm {
     pub res: [u32; 16], // reserved
 }
 
-type Pcbf = extern "C" fn(n_code: u32, vp: *mut c_void) -> u32;
-
-fn show_message_box(message: &str, title: &str) {
-    let lp_text = CString::new(message).unwrap();
-    let lp_caption = CString::new(title).unwrap();
-    unsafe {
-        MessageBoxA(
-            std::ptr::null_mut(),
-            lp_text.as_ptr(),
-            lp_caption.as_ptr(),
-            MB_OK | MB_ICONINFORMATION,
-        );
+/// 一段数据覆盖到、且之前没有擦过的块，合并为连续的 (起始, 结束) 范围
+///
+/// 块从区域的起始地址开始划分，块大小不必是 2 的幂；erased 记录已擦除块的地址
+fn blocks_to_erase(
+    region: &MemoryRegion,
+    start: u32,
+    len: u32,
+    erased: &mut BTreeSet<u64>,
+) -> Vec<(u64, u64)> {
+    let block = region.blocksize.filter(|&b| b > 0).unwrap_or(1024);
+    let first = (start as u64 - region.start) / block;
+    let last = (start as u64 + len as u64 - region.start).div_ceil(block);
+
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for i in first..last {
+        let addr = region.start + i * block;
+        if !erased.insert(addr) {
+            continue;
+        }
+        let end = (addr + block).min(region.end());
+        match ranges.last_mut() {
+            Some((_, e)) if *e == addr => *e = end,
+            _ => ranges.push((addr, end)),
+        }
     }
+    ranges
 }
 
-#[inline]
-fn align_up(value: u32, align: u32) -> u32 {
-    debug_assert!(align.is_power_of_two());
-    (value + align - 1) & !(align - 1)
+/// 目标运行时每次等待停止回复的时间，期间持有 Agdi 的锁
+const RUN_POLL_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// 两次轮询之间释放锁的时间，uVision 在目标运行时的调用可以拿到锁
+const RUN_POLL_INTERVAL: Duration = Duration::from_millis(40);
+
+/// 下载时每个 vFlashWrite / X 包的数据长度
+const WRITE_CHUNK: usize = 256;
+
+/// Cortex-M 的向量表偏移寄存器
+const VTOR: u32 = 0xE000_ED08;
+
+/// 整片擦除时每个 vFlashErase 包含的块数
+const MASS_ERASE_BLOCKS: u64 = 16;
+
+/// 下载结束时是否已经让目标运行
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum AfterDownload {
+    #[default]
+    Stopped,
+    /// 按 post_download 已经 reset run
+    ResetRun,
+    /// RAM 镜像已经开始运行，断开时不能 kill
+    RamRun,
 }
 
+/// 已经发出的 go 命令，目标停下后由 go_poll 完成
+#[derive(Debug, Clone, Copy)]
+struct RunningGo {
+    n_code: u16,
+    /// go until address 的临时断点，停下后移除
+    temporary: Option<u32>,
+}
 
-pub struct Agdi {
-    p_callback: Option<Pcbf>,
-    gdb_client: GdbClient<TcpTransport>,
+pub struct Agdi<
+    T: GdbTransport = RecordingTransport<TcpTransport>,
+    H: Host = KeilHost,
+    N: Notifier = DefaultNotifier,
+> {
+    config: Config,
+    host: H,
+    notifier: N,
+    gdb_client: GdbClient<T>,
+    state: TargetState,
+    last_stop: Option<StopReply>,
+    go: Option<RunningGo>,
+    after_download: AfterDownload,
+    bp_head: BpHead,
+    breakpoints: BreakpointManager,
+    memory_map: Option<MemoryMap>,
+    features: FeatureTable,
 }
 
 impl Agdi {
     pub fn new() -> Self {
+        let config = Config::load().unwrap_or_else(|e| {
+            DefaultNotifier::default().notify(
+                &format!("Invalid configuration, using defaults: {}", e),
+                "Error",
+            );
+            Config::default()
+        });
+        if let Err(e) = logging::init(&config) {
+            DefaultNotifier::default().notify(&format!("Cannot open log file: {}", e), "Error");
+        }
+        info!(
+            "openocd_agdi {}, server {}:{}",
+            env!("CARGO_PKG_VERSION"),
+            config.host,
+            config.port
+        );
+        Self::with_config(config)
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        let mut notifier = DefaultNotifier::default();
+        let mut transport =
+            RecordingTransport::new(TcpTransport::new(config.host.clone(), config.port));
+        if let Some(path) = &config.record
+            && let Err(e) = transport.record_to(path)
+        {
+            notifier.notify(
+                &format!("Cannot record to {}: {}", path.display(), e),
+                "Error",
+            );
+        }
+        Self::with_parts(config, transport, KeilHost::default(), notifier)
+    }
+}
+
+impl<T: GdbTransport, H: Host, N: Notifier> Agdi<T, H, N> {
+    pub fn with_parts(config: Config, transport: T, host: H, notifier: N) -> Self {
         Self {
-            p_callback: None,
-            gdb_client: GdbClient::new(TcpTransport::new("localhost", 3333)),
+            config,
+            host,
+            notifier,
+            gdb_client: GdbClient::new(transport),
+            state: TargetState::Disconnected,
+            last_stop: None,
+            go: None,
+            after_download: AfterDownload::Stopped,
+            bp_head: BpHead(std::ptr::null_mut()),
+            breakpoints: BreakpointManager::new(
+                DEFAULT_HW_BREAKPOINTS,
+                DEFAULT_WATCHPOINTS,
+                DEFAULT_MAX_WATCH_LEN,
+            ),
+            memory_map: None,
+            features: FeatureTable::default(),
         }
     }
+
     pub fn init(&mut self, n_code: u16, _vp: *mut c_void) -> u32 {
         match n_code & 0xFF00 {
             AG_INITITEM => match n_code & 0x00FF {
                 AG_INITFLASHLOAD => self.init_flash_load(),
                 AG_STARTFLASHLOAD => self.start_flash_load(),
+                AG_INITBPHEAD => {
+                    self.bp_head = BpHead(_vp as *mut *mut AG_Bps);
+                    AG_OK
+                }
                 AG_INITCALLBACK => {
                     // 初始化回调函数指针
-                    self.init_callback(_vp);
+                    self.host.set_callback(_vp);
                     AG_OK
                 }
                 _ => AG_OK,
             },
-            AG_GETFEATURE => 0,
+            AG_EXECITEM => match n_code & 0x00FF {
+                AG_UNINIT => self.end_debug_session(),
+                AG_RESET => self.reset(),
+                _ => AG_OK,
+            },
+            AG_INITFEATURES => self.init_features(),
+            AG_GETFEATURE => self.features.query(n_code & 0x00FF),
             _ => AG_OK,
         }
     }
 
-    // pub fn mem_att(&self, _n_code: u16, _n_attr: u32, _pa: *mut GADR) -> u32 {
-    //     AG_NOACCESS
-    // }
-
     pub fn dll_uv3_cap(&self, n_code: u32, _vp: *mut c_void) -> i32 {
         match n_code {
             1 => 1,
@@ -181,137 +344,1336 @@ impl Agdi {
         }
     }
 
-    pub fn init_callback(&mut self, vp: *mut c_void) {
-        if !vp.is_null() {
-            let cb: Pcbf = unsafe { std::mem::transmute(vp) };
-            self.p_callback = Some(cb);
+    /// 无法继续调试的错误，弹出消息框
+    fn report(&mut self, message: &str, title: &str) {
+        error!("{}: {}", title, message);
+        self.notifier.notify(message, title);
+    }
+
+    /// 下载、断点等操作的失败，输出到 Command 窗口
+    fn report_output(&mut self, message: &str) {
+        error!("{}", message);
+        self.host.message(message);
+    }
+
+    /// 上一次调用中途 panic，服务器和目标的状态都不可信
+    fn recover_from_panic(&mut self) {
+        warn!("previous call panicked, dropping the connection");
+        self.gdb_client.disconnect();
+        self.on_disconnected();
+    }
+
+    fn on_disconnected(&mut self) {
+        self.apply_event(TargetEvent::Disconnected);
+        self.go = None;
+        self.breakpoints.forget_all();
+        self.memory_map = None;
+        self.features = FeatureTable::default();
+    }
+
+    fn apply_event(&mut self, event: TargetEvent) {
+        if let Some(next) = self.state.next(event) {
+            self.state = next;
+        }
+    }
+
+    fn on_stop_reply(&mut self, reply: StopReply) {
+        if let Some(event) = TargetEvent::from_stop_reply(&reply) {
+            self.apply_event(event);
+        }
+        self.last_stop = Some(reply);
+    }
+
+    fn require_state(&self, op: &str, ok: fn(TargetState) -> bool) -> Result<()> {
+        if ok(self.state) {
+            Ok(())
+        } else {
+            Err(Error::TargetState {
+                op: op.into(),
+                state: self.state,
+            })
+        }
+    }
+
+    /// 连接后用 `?` 获取目标初始状态
+    fn refresh_state(&mut self) -> Result<()> {
+        let reply = self.gdb_client.query_halt_reason()?;
+        self.on_stop_reply(reply);
+        Ok(())
+    }
+
+    /// AG_GoStep 的开始：立即完成的命令返回结果；目标开始运行时返回 None，
+    /// 之后由 go_poll 轮询，两次轮询之间不持有锁
+    pub fn go_begin(&mut self, n_code: u16, n_steps: u32, pa: *mut GADR) -> Option<u32> {
+        let result = match n_code {
+            AG_STOPRUN => self.stop_run(),
+            AG_NSTEP => self.step_n(n_steps),
+            AG_GOTILADR | AG_GOFORBRK => match self.start_go(n_code, pa) {
+                Ok(()) => return None,
+                Err(e) => self.end_go(Err(e)),
+            },
+            _ => return Some(AG_NOACCESS),
+        };
+        Some(self.go_status(n_code, result, pa))
+    }
+
+    /// 等待一次停止回复，目标停下后返回 AG_GoStep 的结果
+    pub fn go_poll(&mut self, pa: *mut GADR, timeout: Duration) -> Option<u32> {
+        let result = match self.poll_run(timeout) {
+            Ok(false) => return None,
+            Ok(true) => Ok(()),
+            Err(e) => Err(e),
+        };
+        let n_code = self.go.map_or(AG_GOFORBRK, |g| g.n_code);
+        let result = self.end_go(result);
+        Some(self.go_status(n_code, result, pa))
+    }
+
+    /// 测试用：持有锁直到目标停下
+    #[cfg(test)]
+    pub fn go_step(&mut self, n_code: u16, n_steps: u32, pa: *mut GADR) -> u32 {
+        let mut status = self.go_begin(n_code, n_steps, pa);
+        loop {
+            if let Some(status) = status {
+                return status;
+            }
+            status = self.go_poll(pa, RUN_POLL_TIMEOUT);
+        }
+    }
+
+    fn go_status(&mut self, n_code: u16, result: Result<()>, pa: *mut GADR) -> u32 {
+        match result {
+            Ok(()) => {
+                self.report_stop(pa);
+                AG_OK
+            }
+            Err(e) => {
+                error!("go/step {:#x} failed: {}", n_code, e);
+                e.ag_code()
+            }
+        }
+    }
+
+    /// 运行中的 go 会在下一次轮询时发现目标已经停下
+    fn stop_run(&mut self) -> Result<()> {
+        if self.state != TargetState::Running {
+            return Ok(());
+        }
+
+        self.gdb_client.interrupt()?;
+        let reply = self.gdb_client.wait_stop_reply()?;
+        self.on_stop_reply(reply);
+        Ok(())
+    }
+
+    fn step_n(&mut self, n_steps: u32) -> Result<()> {
+        for _ in 0..n_steps.max(1) {
+            self.require_state("step", TargetState::can_resume)?;
+            self.apply_event(TargetEvent::Resumed);
+            let reply = self.gdb_client.step()?;
+            self.on_stop_reply(reply);
+        }
+        Ok(())
+    }
+
+    /// go until address 在目标地址放一个临时断点再运行
+    fn start_go(&mut self, n_code: u16, pa: *mut GADR) -> Result<()> {
+        self.require_state("go", TargetState::can_resume)?;
+        self.sync_breakpoints(None)?;
+
+        let mut temporary = None;
+        if n_code == AG_GOTILADR && !pa.is_null() {
+            let addr = unsafe { (*pa).adr };
+            if !self.breakpoints.is_installed(addr) {
+                let kind = BreakpointKind::for_address(addr, self.load_memory_map()?);
+                self.breakpoints.insert(&mut self.gdb_client, addr, kind)?;
+                temporary = Some(addr);
+            }
+        }
+        self.go = Some(RunningGo { n_code, temporary });
+
+        self.gdb_client.resume()?;
+        self.apply_event(TargetEvent::Resumed);
+        Ok(())
+    }
+
+    /// go 命令结束，目标停下时移除临时断点
+    fn end_go(&mut self, result: Result<()>) -> Result<()> {
+        match self.go.take().and_then(|g| g.temporary) {
+            Some(addr) if self.state.is_halted() => {
+                result.and(self.breakpoints.remove(&mut self.gdb_client, addr))
+            }
+            _ => result,
+        }
+    }
+
+    /// 返回 true 表示目标已经停下且不需要继续运行：
+    /// 计数未到、条件不成立或带命令的断点会透明地继续运行
+    fn poll_run(&mut self, timeout: Duration) -> Result<bool> {
+        // AG_STOPRUN 已经停住了目标
+        if self.state != TargetState::Running {
+            return Ok(true);
+        }
+        let Some(reply) = self.gdb_client.poll_stop_reply(timeout)? else {
+            return Ok(false);
+        };
+        self.on_stop_reply(reply);
+        if !self.state.is_halted() {
+            return Ok(true);
+        }
+
+        let pc = self.current_pc()?;
+        if !self.should_resume_after_break(pc) {
+            return Ok(true);
+        }
+
+        // 运行期间修改的断点在继续运行前同步
+        self.sync_breakpoints(None)?;
+        self.step_over_breakpoint(pc)?;
+        if !self.state.can_resume() {
+            return Ok(true);
+        }
+        self.gdb_client.resume()?;
+        self.apply_event(TargetEvent::Resumed);
+        Ok(false)
+    }
+
+    fn current_pc(&mut self) -> Result<u32> {
+        let expedited = match &self.last_stop {
+            Some(StopReply::Stopped(ev)) => ev.pc(),
+            _ => None,
+        };
+        match expedited {
+            Some(pc) => Ok(pc),
+            None => self.gdb_client.read_register(REG_PC),
+        }
+    }
+
+    /// 在停止位置找到对应的 Keil 断点
+    fn hit_keil_bp(&self, pc: u32) -> Option<*mut AG_Bps> {
+        let watch = self.watch_hit();
+        self.keil_bps().into_iter().find(|&p| {
+            let bp = unsafe { &*p };
+            if !bp.enabled() {
+                return false;
+            }
+            match (bp.bp_type(), watch) {
+                (AG_WBREAK, Some(addr)) => bp.adr == addr,
+                (AG_ABREAK, None) => bp.adr == pc,
+                _ => false,
+            }
+        })
+    }
+
+    /// Keil 断点的计数、条件和命令在主机侧处理，返回 true 表示应继续运行
+    fn should_resume_after_break(&mut self, pc: u32) -> bool {
+        let Some(p) = self.hit_keil_bp(pc) else {
+            return false;
+        };
+        let (ep, cmd, rcount, ocount) = unsafe { ((*p).ep, (*p).cmd, (*p).rcount, (*p).ocount) };
+
+        // 条件由 Keil 求值，不成立时不计数
+        if !ep.is_null() && !self.host.eval_condition(ep) {
+            return true;
+        }
+
+        if rcount > 1 {
+            unsafe { (*p).rcount = rcount - 1 };
+            return true;
+        }
+        // 断点生效，按 Keil 的约定从 ocount 重新装载计数
+        unsafe { (*p).rcount = ocount.max(1) };
+
+        // 带命令的断点执行命令后继续运行
+        if !cmd.is_null() {
+            self.host.exec_command(cmd as *mut c_void);
+            return true;
+        }
+        false
+    }
+
+    /// 从断点地址继续运行前，先临时移除断点单步越过它
+    fn step_over_breakpoint(&mut self, pc: u32) -> Result<()> {
+        let kind = self.breakpoints.kind_at(pc);
+        if kind.is_some() {
+            self.breakpoints.remove(&mut self.gdb_client, pc)?;
+        }
+
+        self.apply_event(TargetEvent::Resumed);
+        let reply = self.gdb_client.step()?;
+        self.on_stop_reply(reply);
+
+        if let Some(kind) = kind {
+            self.breakpoints.insert(&mut self.gdb_client, pc, kind)?;
+        }
+        Ok(())
+    }
+
+    /// 停止后把当前 PC 写回给 Keil，观察点触发时 err_adr 为被访问的数据地址
+    fn report_stop(&mut self, pa: *mut GADR) {
+        if pa.is_null() || !self.state.is_halted() {
+            return;
+        }
+
+        if let Some(addr) = self.watch_hit() {
+            unsafe { (*pa).err_adr = addr };
+        }
+
+        if let Ok(pc) = self.current_pc() {
+            unsafe { (*pa).adr = pc };
+        }
+    }
+
+    /// 上一次停止是否由观察点触发，返回数据地址
+    fn watch_hit(&self) -> Option<u32> {
+        match &self.last_stop {
+            Some(StopReply::Stopped(StopEvent {
+                reason: StopReason::Watchpoint { addr, .. },
+                ..
+            })) => Some(
+                self.breakpoints
+                    .watchpoint_at(*addr)
+                    .map_or(*addr, |w| w.addr),
+            ),
+            _ => None,
+        }
+    }
+
+    pub fn break_func(&mut self, n_code: u16, n1: u16, _pa: *mut GADR, pb: *mut AG_Bps) -> u32 {
+        let result = match n_code {
+            AG_BPINSREM | AG_BPACTIVATE => self.sync_breakpoints(Some((pb, n1 != 0))),
+            AG_BPTOGGLE => self.sync_breakpoints(None),
+            AG_BPDISALL | AG_BPKILLALL => self.clear_breakpoints(),
+            _ => Ok(()),
+        };
+
+        match result {
+            Ok(()) => AG_OK,
+            Err(e) => {
+                self.report_output(&format!("Breakpoint: {}", e));
+                e.ag_code()
+            }
+        }
+    }
+
+    pub fn bp_info(&self, n_code: u16, vp: *mut c_void) -> u32 {
+        match n_code {
+            // vp: GADR，查询该地址上是否已插入断点
+            AG_BPQUERY if !vp.is_null() => {
+                let addr = unsafe { (*(vp as *mut GADR)).adr };
+                self.breakpoints.is_installed(addr) as u32
+            }
+            _ => 0,
+        }
+    }
+
+    /// 连接期间内存映射不变，只读取一次
+    fn load_memory_map(&mut self) -> Result<&MemoryMap> {
+        if self.memory_map.is_none() {
+            self.memory_map = Some(self.gdb_client.get_memory_map()?);
         }
+        Ok(self.memory_map.get_or_insert_default())
     }
+
+    pub fn mem_att(&mut self, n_code: u16, _n_attr: u32, pa: *mut GADR) -> u32 {
+        if n_code != AG_GETMEMATT || pa.is_null() || !self.state.is_connected() {
+            return AG_NOACCESS;
+        }
+
+        let (addr, len) = unsafe { ((*pa).adr, (*pa).n_len) };
+        match self.load_memory_map() {
+            Ok(map) => map.attributes(addr, len),
+            Err(e) => {
+                error!("reading memory map failed: {}", e);
+                e.ag_code()
+            }
+        }
+    }
+
+    fn keil_bps(&self) -> Vec<*mut AG_Bps> {
+        let mut out = Vec::new();
+        if self.bp_head.0.is_null() {
+            return out;
+        }
+
+        let mut p = unsafe { *self.bp_head.0 };
+        while !p.is_null() {
+            out.push(p);
+            p = unsafe { (*p).next };
+        }
+        out
+    }
+
+    /// 根据 Keil 的断点链表计算应当插入的执行断点和观察点，
+    /// over 用来覆盖正在通知的断点的使能状态（通知时链表可能尚未更新）
+    fn wanted_breakpoints(
+        &mut self,
+        over: Option<(*mut AG_Bps, bool)>,
+    ) -> Result<(Vec<InstalledBreakpoint>, Vec<Watchpoint>)> {
+        let map = self.load_memory_map()?.clone();
+
+        let mut list = self.keil_bps();
+        if let Some((pb, _)) = over
+            && !pb.is_null()
+            && !list.contains(&pb)
+        {
+            list.push(pb);
+        }
+
+        let mut wanted: Vec<InstalledBreakpoint> = Vec::new();
+        let mut watches: Vec<Watchpoint> = Vec::new();
+        for p in list {
+            let bp = unsafe { &*p };
+            let enabled = match over {
+                Some((pb, enabled)) if pb == p => enabled,
+                _ => bp.enabled(),
+            };
+            if !enabled {
+                continue;
+            }
+
+            if bp.bp_type() == AG_WBREAK {
+                let wp = Watchpoint::from_access(bp.acc, bp.adr, bp.watch_len()?)?;
+                if !watches.contains(&wp) {
+                    watches.push(wp);
+                }
+                continue;
+            }
+            if bp.bp_type() != AG_ABREAK {
+                continue;
+            }
+
+            let addr = bp.adr;
+            if wanted.iter().any(|w| w.addr == addr) {
+                continue;
+            }
+            wanted.push(InstalledBreakpoint {
+                addr,
+                kind: BreakpointKind::for_address(addr, &map),
+            });
+        }
+        Ok((wanted, watches))
+    }
+
+    /// 目标停止时立即同步，否则等到下次运行前再同步
+    fn sync_breakpoints(&mut self, over: Option<(*mut AG_Bps, bool)>) -> Result<()> {
+        if !self.state.is_halted() {
+            return Ok(());
+        }
+
+        let (wanted, watches) = self.wanted_breakpoints(over)?;
+        self.breakpoints.sync(&mut self.gdb_client, &wanted)?;
+        self.breakpoints
+            .sync_watchpoints(&mut self.gdb_client, &watches)
+    }
+
+    fn clear_breakpoints(&mut self) -> Result<()> {
+        if self.state.is_halted() {
+            self.breakpoints.remove_all(&mut self.gdb_client)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// 调试会话开始：连接服务器、停住目标、读取初始状态，并建立功能表
+    pub fn init_features(&mut self) -> u32 {
+        match self.start_debug_session() {
+            Ok(()) => AG_OK,
+            Err(e) => {
+                self.gdb_client.disconnect();
+                self.on_disconnected();
+                self.report(&format!("Failed to connect to GDB server: {}", e), "Error");
+                e.ag_code()
+            }
+        }
+    }
+
+    fn start_debug_session(&mut self) -> Result<()> {
+        self.after_download = AfterDownload::Stopped;
+        self.gdb_client.connect()?;
+        let server = self.gdb_client.query_supported()?;
+        self.refresh_state()?;
+
+        if self.config.halt_on_connect && self.state == TargetState::Running {
+            self.gdb_client.interrupt()?;
+            let reply = self.gdb_client.wait_stop_reply()?;
+            self.on_stop_reply(reply);
+        }
+
+        self.load_memory_map()?;
+
+        // 目标运行时不能读取，沿用默认的比较器数量
+        let (mem_access, reg_access) = if self.state.is_halted() {
+            let mem_access = self.breakpoints.probe_limits(&mut self.gdb_client);
+            (mem_access, self.gdb_client.read_register(REG_PC).is_ok())
+        } else {
+            (true, true)
+        };
+        debug!("debug session started, target {:?}", self.state);
+        self.features = FeatureTable::from_server(
+            &server,
+            TargetCaps {
+                hw_breakpoints: self.breakpoints.hw_limit(),
+                watchpoints: self.breakpoints.wp_limit(),
+                mem_access,
+                reg_access,
+            },
+        );
+        Ok(())
+    }
+
+    /// 调试会话结束：移除断点，按配置 detach 或让目标继续运行，再断开连接
+    pub fn end_debug_session(&mut self) -> u32 {
+        if !self.state.is_connected() {
+            return AG_OK;
+        }
+
+        match self.close_session(self.config.exit_action) {
+            Ok(()) => AG_OK,
+            Err(e) => {
+                error!("ending debug session failed: {}", e);
+                e.ag_code()
+            }
+        }
+    }
+
+    fn close_session(&mut self, action: ExitAction) -> Result<()> {
+        let result = self.teardown(action);
+
+        let mode = match action {
+            ExitAction::Detach => DisconnectMode::Detach,
+            ExitAction::Kill => DisconnectMode::Kill,
+            ExitAction::Resume | ExitAction::Drop => DisconnectMode::Drop,
+        };
+        // teardown 失败时服务器状态不可信，直接关闭
+        let closed = match result {
+            Ok(()) => self.gdb_client.disconnect_with(mode),
+            Err(_) => self.gdb_client.disconnect_with(DisconnectMode::Drop),
+        };
+        self.on_disconnected();
+
+        result.and(closed)
+    }
+
+    fn teardown(&mut self, action: ExitAction) -> Result<()> {
+        // 没有断点时不打断运行中的目标，例如下载后已经 reset run
+        if self.state == TargetState::Running && !self.breakpoints.is_empty() {
+            self.stop_run()?;
+        }
+        if self.state.is_halted() {
+            self.breakpoints.remove_all(&mut self.gdb_client)?;
+        }
+
+        if action == ExitAction::Resume && self.state.can_resume() {
+            self.gdb_client.resume()?;
+            self.apply_event(TargetEvent::Resumed);
+        }
+        Ok(())
+    }
+
     pub fn init_flash_load(&mut self) -> u32 {
-        match self.gdb_client.connect() {
+        let connected = self.gdb_client.connect();
+        match connected.and_then(|_| self.refresh_state()) {
             Ok(_) => AG_OK,
             Err(e) => {
-                show_message_box(&format!("Failed to connect to GDB server: {}", e), "Error");
-                AG_NOACCESS
+                self.report(&format!("Failed to connect to GDB server: {}", e), "Error");
+                e.ag_code()
             }
         }
     }
 
     fn do_flash_load_internal(&mut self) -> u32 {
-        self.progress_bar_init("Loading...");
+        if !self.state.is_connected() {
+            error!("download requested while not connected");
+            return AG_NOACCESS;
+        }
 
-        // 获取 flash 信息
-        let flash_infs = match self.gdb_client.get_flash_info() {
-            Ok(i) => i,
-            Err(_) => return AG_NOACCESS,
+        // 在擦除前拒绝未确认的不可逆设置
+        if let Err(e) = self.config.option_bytes.check() {
+            self.report_output(&format!("Option bytes: {}", e));
+            return e.ag_code();
+        }
+
+        // 配置的命令失败表示下载被中止，而不是写入失败
+        let cmds = self.config.pre_flash_cmds.clone();
+        if let Err(e) = self.run_monitor_cmds(&cmds) {
+            self.report_output(&format!("Pre-download command failed:\n{}", e));
+            return AG_NOACCESS;
+        }
+
+        // 读保护时擦除和写入只会得到 Exx，先检查并按配置解除
+        let protected = self.config.protection.is_protected(&mut self.gdb_client);
+        let unlocked = match protected {
+            Ok(true) => self.config.protection.unlock(&mut self.gdb_client),
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
         };
+        if let Err(e) = unlocked {
+            self.report_output(&format!("Read-out protection: {}", e));
+            return e.ag_code();
+        }
 
-        if flash_infs.len() == 0 {
+        // 没有回调时拿不到下载数据，不能当作擦除命令
+        if !self.host.has_callback() {
+            error!("download requested without AG_INITCALLBACK");
             return AG_NOACCESS;
         }
+        let first = self.host.flash_param(core::ptr::null_mut());
+        if first.is_null() {
+            self.report_output("Download failed: uVision returned no flash parameters");
+            return AG_NOACCESS;
+        }
+        // Keil 的 Erase 命令给出一个空的 FlashParm 链
+        if unsafe { (*first).many } == 0 {
+            return match self.mass_erase() {
+                Ok(()) => AG_OK,
+                Err(e) => {
+                    self.report_output(&format!("Erase failed: {}", e));
+                    e.ag_code()
+                }
+            };
+        }
 
-        // 获取第一个 flash
-        let flash_inf = &flash_infs[0];
-        let block_size = flash_inf.blocksize.unwrap_or(1024);
+        self.host.progress_init("Loading...");
+        let loaded = self.load_image_unlocked(first);
+        self.host.progress_kill();
+        let ram_base = match loaded {
+            Ok(base) => base,
+            Err(e) => {
+                self.report_output(&format!("Download failed: {}", e));
+                return e.ag_code();
+            }
+        };
 
-        let mut wrote_bytes = 0;
-        let mut pf = unsafe { &mut *self.get_flash_param(core::ptr::null_mut()) };
+        let cmds = self.config.post_flash_cmds.clone();
+        if let Err(e) = self.run_monitor_cmds(&cmds) {
+            self.report_output(&format!("Post-download command failed:\n{}", e));
+            return AG_NOACCESS;
+        }
 
-        // 擦除
-        if pf.many != 0 {
-            let earse_size = align_up(pf.act_size, block_size as u32);
-            match self.gdb_client.flash_erase(pf.start, earse_size) {
-                Ok(_) => {}
-                Err(_) => return AG_NOACCESS,
-            };
+        if let Err(e) = self.program_option_bytes() {
+            self.report_output(&format!("Option bytes failed:\n{}", e));
+            return e.ag_code();
         }
 
-        loop {
-            if pf.many == 0 {
-                break;
+        let reset = match self.config.post_download {
+            // RAM 镜像复位后会丢失
+            _ if self.config.ram_run && ram_base.is_some() => {
+                let vector_table = self.config.ram_vector_table.or(ram_base);
+                let started = self.start_ram_image(vector_table.unwrap_or_default());
+                if started.is_ok() {
+                    self.after_download = AfterDownload::RamRun;
+                }
+                started
+            }
+            PostDownload::None => Ok(()),
+            PostDownload::ResetRun => {
+                let reset = self.reset_target(true);
+                if reset.is_ok() {
+                    self.after_download = AfterDownload::ResetRun;
+                }
+                reset
             }
+            PostDownload::ResetHalt => self.reset_target(false),
+        };
+        if let Err(e) = reset {
+            self.report_output(&format!("Reset after download failed: {}", e));
+            return e.ag_code();
+        }
+
+        AG_OK
+    }
+
+    /// 读保护的芯片擦除和写入都只会回复 Exx：确认保护后按配置解除，再重新下载一次
+    fn load_image_unlocked(&mut self, first: *mut FlashParm) -> Result<Option<u32>> {
+        let e = match self.load_image(first) {
+            Err(e @ Error::Server { .. }) => e,
+            loaded => return loaded,
+        };
+
+        let protection = self.config.protection.clone();
+        let checked = protection.check.is_some();
+        if checked && !protection.is_protected(&mut self.gdb_client)? {
+            return Err(e);
+        }
+        // 没有配置检查方法时无法确认，只能提示
+        if !checked && !protection.can_unlock() {
+            self.report_output(
+                "The server rejected the download; the chip may be read-out protected, \
+                 configure [protection] to detect and unlock it",
+            );
+            return Err(e);
+        }
 
+        warn!("{}; unlocking read-out protection and retrying", e);
+        protection.unlock(&mut self.gdb_client)?;
+        self.report_output("Read-out protection removed, downloading again");
+        self.load_image(first)
+    }
+
+    /// 按内存映射把每段写到 flash 或 RAM，返回 RAM 段的最低地址
+    fn load_image(&mut self, first: *mut FlashParm) -> Result<Option<u32>> {
+        let map = self.load_memory_map()?.clone();
+        let started = Instant::now();
+        let mut erased = BTreeSet::new();
+        let mut ram_base: Option<u32> = None;
+        let mut wrote_bytes = 0u64;
+
+        let mut pf = first;
+        while !pf.is_null() && unsafe { (*pf).many } != 0 {
+            let (start, many, act_size) = unsafe { ((*pf).start, (*pf).many, (*pf).act_size) };
             let data: &[u8] =
-                unsafe { slice::from_raw_parts(pf.image as *const u8, pf.many as usize) };
-            match self.gdb_client.flash_write(pf.start, data, 256) {
-                Ok(_) => {}
-                Err(_) => return AG_NOACCESS,
+                unsafe { slice::from_raw_parts((*pf).image as *const u8, many as usize) };
+
+            let Some(pieces) = map.split_range(start, many) else {
+                return Err(Error::Unmapped {
+                    addr: start,
+                    len: many,
+                });
             };
-            wrote_bytes += pf.many;
-            self.progress_bar_setpos((wrote_bytes * 100 / pf.act_size) as i32);
-            // get next param
-            pf = unsafe { &mut *self.get_flash_param(pf) };
-        }
-        match self.gdb_client.flash_done() {
-            Ok(_) => {}
-            Err(_) => return AG_NOACCESS,
-        };
-        self.progress_bar_kill();
 
-        AG_OK
+            // 跨越区域边界的段按区域分开，各用自己的块大小擦除
+            for (region, addr, len) in pieces {
+                let offset = (addr - start) as usize;
+                let piece = &data[offset..offset + len as usize];
+                match region.kind {
+                    MemoryKind::Ram => {
+                        debug!("write {:#x} bytes to RAM at {:#010x}", len, addr);
+                        self.gdb_client.write_binary(addr, piece, WRITE_CHUNK)?;
+                        ram_base = Some(ram_base.map_or(addr, |b| b.min(addr)));
+                    }
+                    MemoryKind::Flash => {
+                        // Keil 给出的段不一定按地址排序
+                        for (from, to) in blocks_to_erase(region, addr, len, &mut erased) {
+                            let t = Instant::now();
+                            self.gdb_client
+                                .flash_erase(from as u32, (to - from) as u32)?;
+                            debug!("erased {:#010x}..{:#010x} in {:?}", from, to, t.elapsed());
+                        }
+                        let t = Instant::now();
+                        self.gdb_client.flash_write(addr, piece, WRITE_CHUNK)?;
+                        debug!(
+                            "wrote {:#x} bytes at {:#010x} in {:?}",
+                            len,
+                            addr,
+                            t.elapsed()
+                        );
+                    }
+                    MemoryKind::Rom => {
+                        return Err(Error::ReadOnly { addr });
+                    }
+                }
+            }
+
+            wrote_bytes += many as u64;
+            self.host
+                .progress_set((wrote_bytes * 100 / act_size.max(1) as u64) as i32);
+            pf = self.host.flash_param(pf);
+        }
+
+        self.gdb_client.flash_done()?;
+        debug!(
+            "downloaded {} bytes in {:?}",
+            wrote_bytes,
+            started.elapsed()
+        );
+        Ok(ram_base)
     }
 
-    pub fn start_flash_load(&mut self) -> u32 {
-        let result = self.do_flash_load_internal();
-        self.gdb_client.disconnect();
+    /// 从向量表取 SP / PC 后运行 RAM 中的镜像
+    fn start_ram_image(&mut self, vector_table: u32) -> Result<()> {
+        let sp = self.gdb_client.read_u32(vector_table)?;
+        let pc = self.gdb_client.read_u32(vector_table + 4)?;
+
+        // Cortex-M0 没有 VTOR，写入会被忽略
+        self.gdb_client
+            .write_memory(VTOR, &vector_table.to_le_bytes())?;
+        self.gdb_client.write_register(REG_SP, sp)?;
+        // 复位向量带 Thumb 位，PC 本身不带
+        self.gdb_client.write_register(REG_PC, pc & !1)?;
+
+        self.gdb_client.resume()?;
+        self.apply_event(TargetEvent::Resumed);
+        Ok(())
+    }
+
+    /// 整片擦除：优先使用配置的 monitor 命令，否则逐块擦除内存映射中的所有 flash
+    fn mass_erase(&mut self) -> Result<()> {
+        let started = Instant::now();
+        self.host.progress_init("Erasing...");
+        let result = self.mass_erase_inner();
+        self.host.progress_kill();
+        debug!("mass erase finished in {:?}", started.elapsed());
         result
     }
 
-    pub fn get_flash_param(&self, _vp: *mut FlashParm) -> *mut FlashParm {
-        let ptr: u32 = self.call_callback(AG_CB_GETFLASHPARAM, _vp as *mut _ as *mut c_void);
-        return ptr as usize as *mut FlashParm;
+    fn mass_erase_inner(&mut self) -> Result<()> {
+        if let Some(cmd) = self.config.mass_erase_cmd.clone() {
+            self.gdb_client.monitor(&cmd)?;
+            self.host.progress_set(100);
+            return Ok(());
+        }
+
+        let regions: Vec<MemoryRegion> = self
+            .load_memory_map()?
+            .flash_regions()
+            .filter(|r| r.length > 0)
+            .cloned()
+            .collect();
+        if regions.is_empty() {
+            return Err(Error::Malformed {
+                op: "memory-map".into(),
+                kind: OpKind::Other,
+                reply: "no flash regions".into(),
+            });
+        }
+
+        let total: u64 = regions.iter().map(|r| r.length).sum();
+        let mut erased = 0u64;
+        for r in &regions {
+            // 每次擦除若干个块，进度条才有意义
+            let block = r.blocksize.filter(|&b| b > 0).unwrap_or(1024);
+            let step = block * MASS_ERASE_BLOCKS;
+            let mut addr = r.start;
+            while addr < r.end() {
+                let len = step.min(r.end() - addr);
+                self.gdb_client.flash_erase(addr as u32, len as u32)?;
+                addr += len;
+                erased += len;
+                self.host.progress_set((erased * 100 / total) as i32);
+            }
+        }
+        self.gdb_client.flash_done()
     }
 
-    pub fn call_callback(&self, n_code: u32, vp: *mut c_void) -> u32 {
-        if let Some(cb) = self.p_callback {
-            // 用消息框显示一下地址
-            cb(n_code, vp)
-        } else {
-            0
+    /// 写入选项字节和 OTP，再回读校验
+    fn program_option_bytes(&mut self) -> Result<()> {
+        let ob = self.config.option_bytes.clone();
+        if ob.is_empty() {
+            return Ok(());
+        }
+        ob.check()?;
+
+        self.run_monitor_cmds(&ob.apply)?;
+        for w in &ob.write {
+            self.gdb_client
+                .write_memory(w.addr, &w.value.to_le_bytes())?;
+        }
+        if !ob.otp.is_empty() {
+            for o in &ob.otp {
+                self.gdb_client
+                    .flash_write(o.addr, &o.value.to_le_bytes(), 256)?;
+            }
+            self.gdb_client.flash_done()?;
+        }
+
+        for v in ob.verify.iter().chain(&ob.otp) {
+            let word = self.gdb_client.read_u32(v.addr)?;
+            if !v.matches(word) {
+                return Err(Error::Verify {
+                    addr: v.addr,
+                    read: word,
+                    expected: v.value,
+                    mask: v.mask,
+                });
+            }
         }
+        Ok(())
     }
 
-    pub fn progress_bar_init(&self, label: &str) -> u32 {
-        let c_label = CString::new(label).unwrap();
-        let mut pg_ress = PgRess {
-            job: PROGRESS_INIT,
-            pos: 0,
-            low: 0,
-            hig: 100,
-            label: c_label.into_raw(),
-            ctext: std::ptr::null_mut(),
-        };
+    /// 依次执行，遇到失败即停止
+    fn run_monitor_cmds(&mut self, cmds: &[String]) -> Result<()> {
+        for cmd in cmds {
+            self.gdb_client.monitor(cmd)?;
+        }
+        Ok(())
+    }
 
-        self.call_callback(AG_CB_PROGRESS, &mut pg_ress as *mut _ as *mut c_void)
+    /// `monitor reset run` / `monitor reset halt`
+    fn reset_target(&mut self, run: bool) -> Result<()> {
+        let cmd = if run { "reset run" } else { "reset halt" };
+        debug!("{}", cmd);
+        self.gdb_client.monitor(cmd)?;
+        self.apply_event(TargetEvent::ResetIssued);
+        self.apply_event(if run {
+            TargetEvent::Resumed
+        } else {
+            TargetEvent::Stopped
+        });
+        Ok(())
     }
 
-    pub fn progress_bar_setpos(&self, pos: i32) -> u32 {
-        let mut pg_ress = PgRess {
-            job: PROGRESS_SETPOS,
-            pos,
-            low: 0,
-            hig: 100,
-            label: std::ptr::null_mut(),
-            ctext: std::ptr::null_mut(),
-        };
-        self.call_callback(AG_CB_PROGRESS, &mut pg_ress as *mut _ as *mut c_void)
+    /// Keil 在下载后选择了 "Reset and Run" 时发送 AG_RESET，此时连接已经关闭
+    fn reset(&mut self) -> u32 {
+        // 下载后目标已经在运行，再复位会重复一次（RAM 镜像还会丢失）
+        if std::mem::take(&mut self.after_download) != AfterDownload::Stopped {
+            debug!("target already started after download, skipping reset");
+            return AG_OK;
+        }
+
+        if self.state.is_connected() {
+            return match self.reset_target(false) {
+                Ok(()) => AG_OK,
+                Err(e) => {
+                    error!("reset failed: {}", e);
+                    e.ag_code()
+                }
+            };
+        }
+
+        let result = self
+            .gdb_client
+            .connect()
+            .and_then(|_| self.refresh_state())
+            .and_then(|_| self.reset_target(true));
+        let _ = self.close_session(ExitAction::Drop);
+        match result {
+            Ok(()) => AG_OK,
+            Err(e) => {
+                self.report_output(&format!("Reset failed: {}", e));
+                e.ag_code()
+            }
+        }
     }
 
-    pub fn progress_bar_kill(&self) -> u32 {
-        let mut pg_ress = PgRess {
-            job: PROGRESS_KILL,
-            pos: 0,
-            low: 0,
-            hig: 100,
-            label: std::ptr::null_mut(),
-            ctext: std::ptr::null_mut(),
+    pub fn start_flash_load(&mut self) -> u32 {
+        self.after_download = AfterDownload::Stopped;
+        let result = self.do_flash_load_internal();
+        // kill 会停止刚刚启动的 RAM 镜像
+        let action = match self.after_download {
+            AfterDownload::RamRun => ExitAction::Drop,
+            AfterDownload::Stopped | AfterDownload::ResetRun => self.config.flash_exit_action,
         };
-        self.call_callback(AG_CB_PROGRESS, &mut pg_ress as *mut _ as *mut c_void)
+        // 下载已经完成，断开时的错误不影响结果
+        let _ = self.close_session(action);
+        result
+    }
+}
+
+#[cfg(test)]
+impl<T: GdbTransport, H: Host, N: Notifier> Agdi<T, H, N> {
+    pub fn notifier(&self) -> &N {
+        &self.notifier
     }
 }
 
 static AGDI_INSTANCE: OnceLock<Mutex<Agdi>> = OnceLock::new();
 
-pub fn get_agdi() -> &'static Mutex<Agdi> {
+fn get_agdi() -> &'static Mutex<Agdi> {
     AGDI_INSTANCE.get_or_init(|| Mutex::new(Agdi::new()))
 }
+
+/// 导出函数的入口：panic 跨越 extern "C" 会使 uVision 崩溃，
+/// 在这里捕获并记录，返回 fallback
+pub fn with_agdi<R: fmt::Debug>(export: &str, fallback: R, f: impl FnOnce(&mut Agdi) -> R) -> R {
+    guarded(get_agdi, export, fallback, f)
+}
+
+fn guarded<'a, T: GdbTransport + 'a, H: Host + 'a, N: Notifier + 'a, R: fmt::Debug>(
+    agdi: impl FnOnce() -> &'a Mutex<Agdi<T, H, N>>,
+    export: &str,
+    fallback: R,
+    f: impl FnOnce(&mut Agdi<T, H, N>) -> R,
+) -> R {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mutex = agdi();
+        // 锁被污染说明之前的调用 panic 了，断开连接后继续使用
+        let mut guard = mutex.lock().unwrap_or_else(|poisoned| {
+            mutex.clear_poison();
+            let mut guard = poisoned.into_inner();
+            guard.recover_from_panic();
+            guard
+        });
+        f(&mut guard)
+    }));
+
+    result.unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        error!("{} panicked: {}, returning {:?}", export, message, fallback);
+        fallback
+    })
+}
+
+/// AG_GoStep：目标运行期间只在轮询时持有锁，运行中 uVision 调用的
+/// AG_BreakFunc、AG_MemAtt、AG_BpInfo 和 AG_STOPRUN 不会被阻塞
+pub fn go_step(n_code: u16, n_steps: u32, pa: *mut GADR) -> u32 {
+    run_go(get_agdi, n_code, n_steps, pa)
+}
+
+fn run_go<'a, T: GdbTransport + 'a, H: Host + 'a, N: Notifier + 'a>(
+    agdi: impl Fn() -> &'a Mutex<Agdi<T, H, N>>,
+    n_code: u16,
+    n_steps: u32,
+    pa: *mut GADR,
+) -> u32 {
+    let mut status = guarded(&agdi, "AG_GoStep", Some(AG_NOACCESS), |a| {
+        a.go_begin(n_code, n_steps, pa)
+    });
+    loop {
+        if let Some(status) = status {
+            return status;
+        }
+        thread::sleep(RUN_POLL_INTERVAL);
+        status = guarded(&agdi, "AG_GoStep", Some(AG_NOACCESS), |a| {
+            a.go_poll(pa, RUN_POLL_TIMEOUT)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agdi_consts::{AG_F_HWBREAK, AG_F_MEMMAP, AG_F_REGACC, AG_F_WATCHP, AG_INVALOP};
+    use crate::host::{NullHost, RecordingNotifier, ScriptedHost};
+    use crate::scripted_transport::ScriptedTransport;
+
+    const MEMORY_MAP: &str = r#"l<memory-map><memory type="flash" start="0x8000000" length="0x10000"><property name="blocksize">0x400</property></memory><memory type="ram" start="0x20000000" length="0x5000"/></memory-map>"#;
+
+    fn agdi(script: ScriptedTransport) -> Agdi<ScriptedTransport, NullHost, RecordingNotifier> {
+        Agdi::with_parts(
+            Config::default(),
+            script,
+            NullHost,
+            RecordingNotifier::default(),
+        )
+    }
+
+    #[test]
+    fn test_debug_session() {
+        let script = ScriptedTransport::new()
+            .expect(
+                "qSupported:swbreak+;hwbreak+",
+                &["PacketSize=4000;qXfer:memory-map:read+"],
+            )
+            .expect("?", &["S05"])
+            .expect("qXfer:memory-map:read::0,fff", &[MEMORY_MAP])
+            // FP_CTRL：8 个比较器，DWT_CTRL：2 个比较器
+            .expect("me0002000,4", &["81000000"])
+            .expect("me0001000,4", &["00000020"])
+            .expect("pf", &["00010008"])
+            .expect("D", &["OK"]);
+        let mut agdi = agdi(script);
+
+        assert_eq!(agdi.init(AG_INITFEATURES, std::ptr::null_mut()), AG_OK);
+        assert_eq!(
+            agdi.init(AG_GETFEATURE | AG_F_HWBREAK, std::ptr::null_mut()),
+            8
+        );
+        assert_eq!(
+            agdi.init(AG_GETFEATURE | AG_F_WATCHP, std::ptr::null_mut()),
+            2
+        );
+        assert_eq!(
+            agdi.init(AG_GETFEATURE | AG_F_REGACC, std::ptr::null_mut()),
+            1
+        );
+        assert_eq!(
+            agdi.init(AG_GETFEATURE | AG_F_MEMMAP, std::ptr::null_mut()),
+            1
+        );
+        assert_eq!(
+            agdi.init(AG_EXECITEM | AG_UNINIT, std::ptr::null_mut()),
+            AG_OK
+        );
+        assert!(agdi.notifier().messages.is_empty());
+    }
+
+    #[test]
+    fn test_connect_failure_notifies() {
+        // 服务器不回复 qSupported
+        let script = ScriptedTransport::new().expect("qSupported:swbreak+;hwbreak+", &[]);
+        let mut agdi = agdi(script);
+
+        assert_eq!(
+            agdi.init(AG_INITFEATURES, std::ptr::null_mut()),
+            AG_NOACCESS
+        );
+        let (message, _) = &agdi.notifier().messages[0];
+        assert!(message.starts_with("Failed to connect to GDB server"));
+        assert_eq!(
+            agdi.init(AG_GETFEATURE | AG_F_HWBREAK, std::ptr::null_mut()),
+            0
+        );
+    }
+
+    const BP_ADDR: u32 = 0x2000_0100;
+    /// 停在断点上，PC 由 T 回复带回
+    const HIT: &str = "T050f:00010020;";
+    const STEPPED: &str = "T050f:02010020;";
+
+    /// 已停止、内存映射已读取的会话
+    fn halted(
+        script: ScriptedTransport,
+    ) -> Agdi<ScriptedTransport, ScriptedHost, RecordingNotifier> {
+        let mut agdi = Agdi::with_parts(
+            Config::default(),
+            script,
+            ScriptedHost::default(),
+            RecordingNotifier::default(),
+        );
+        agdi.memory_map = Some(MemoryMap::parse(&MEMORY_MAP.as_bytes()[1..]).unwrap());
+        agdi.state = TargetState::Halted;
+        agdi
+    }
+
+    fn keil_bp(addr: u32, count: i32) -> Box<AG_Bps> {
+        let mut bp: Box<AG_Bps> = Box::new(unsafe { std::mem::zeroed() });
+        bp.type_enabled_flags = AG_ABREAK | (1 << 4);
+        bp.adr = addr;
+        bp.rcount = count;
+        bp.ocount = count;
+        bp
+    }
+
+    /// 越过断点继续运行：移除、单步、重新插入
+    fn resume_over_bp(script: ScriptedTransport, stop: &str) -> ScriptedTransport {
+        script
+            .expect("z0,20000100,2", &["OK"])
+            .expect("s", &[STEPPED])
+            .expect("Z0,20000100,2", &["OK"])
+            .expect("c", &[stop])
+    }
+
+    fn go(agdi: &mut Agdi<ScriptedTransport, ScriptedHost, RecordingNotifier>, bp: &mut AG_Bps) {
+        let mut head: *mut AG_Bps = bp;
+        agdi.bp_head = BpHead(&mut head);
+        assert_eq!(agdi.go_step(AG_GOFORBRK, 0, std::ptr::null_mut()), AG_OK);
+        agdi.bp_head = BpHead(std::ptr::null_mut());
+    }
+
+    #[test]
+    fn test_breakpoint_insert_failure() {
+        let script = ScriptedTransport::new().expect("Z0,20000100,2", &["E0e"]);
+        let mut agdi = halted(script);
+        let mut bp = keil_bp(BP_ADDR, 1);
+        let mut head: *mut AG_Bps = &mut *bp;
+        agdi.bp_head = BpHead(&mut head);
+
+        let status = agdi.break_func(AG_BPINSREM, 1, std::ptr::null_mut(), &mut *bp);
+        agdi.bp_head = BpHead(std::ptr::null_mut());
+        assert_ne!(status, AG_OK);
+        // 显示在输出窗口，不弹出消息框
+        assert_eq!(
+            agdi.host.messages,
+            vec!["Breakpoint: insert Z0 failed at 0x20000100: server replied E0e"]
+        );
+        assert!(agdi.notifier().messages.is_empty());
+    }
+
+    #[test]
+    fn test_watch_len() {
+        let mut bp = keil_bp(BP_ADDR, 1);
+        bp.type_enabled_flags = AG_WBREAK | (1 << 4);
+        bp.tsize = 4;
+        bp.many = 2;
+        assert_eq!(bp.watch_len().unwrap(), 8);
+
+        // BytObj：many 已经是字节数
+        bp.type_enabled_flags |= 1 << 6;
+        bp.many = 6;
+        assert_eq!(bp.watch_len().unwrap(), 6);
+
+        bp.type_enabled_flags &= !(1 << 6);
+        bp.tsize = 0x1_0000;
+        bp.many = 0x1_0000;
+        assert!(matches!(bp.watch_len(), Err(Error::Breakpoint { .. })));
+
+        // 溢出的观察点报告为断点错误，不会 panic
+        let mut agdi = halted(ScriptedTransport::new());
+        let mut head: *mut AG_Bps = &mut *bp;
+        agdi.bp_head = BpHead(&mut head);
+        let status = agdi.break_func(AG_BPINSREM, 1, std::ptr::null_mut(), &mut *bp);
+        agdi.bp_head = BpHead(std::ptr::null_mut());
+        assert_eq!(status, AG_INVALOP);
+        assert!(agdi.host.messages[0].contains("too large"));
+    }
+
+    #[test]
+    fn test_counted_breakpoint() {
+        // 计数为 3：前两次命中继续运行，第三次停下
+        let script = ScriptedTransport::new()
+            .expect("Z0,20000100,2", &["OK"])
+            .expect("c", &[HIT]);
+        let script = resume_over_bp(script, HIT);
+        let script = resume_over_bp(script, HIT);
+        let mut agdi = halted(script);
+        let mut bp = keil_bp(BP_ADDR, 3);
+
+        go(&mut agdi, &mut bp);
+        assert!(agdi.state.is_halted());
+        // 停下后从 ocount 重新装载
+        assert_eq!({ bp.rcount }, 3);
+    }
+
+    #[test]
+    fn test_breakpoint_condition_false() {
+        // 条件不成立的命中不计数
+        let script = ScriptedTransport::new()
+            .expect("Z0,20000100,2", &["OK"])
+            .expect("c", &[HIT]);
+        let script = resume_over_bp(script, HIT);
+        let script = resume_over_bp(script, HIT);
+        let mut agdi = halted(script);
+        agdi.host.conditions = [false, true, true].into();
+        let mut bp = keil_bp(BP_ADDR, 2);
+        bp.ep = 0x1234 as *mut c_void;
+
+        go(&mut agdi, &mut bp);
+        assert!(agdi.host.conditions.is_empty());
+        assert_eq!({ bp.rcount }, 2);
+    }
+
+    #[test]
+    fn test_breakpoint_command() {
+        // 带命令的断点执行命令后继续运行，之后停在别处
+        let script = ScriptedTransport::new()
+            .expect("Z0,20000100,2", &["OK"])
+            .expect("c", &[HIT]);
+        let script = resume_over_bp(script, "T050f:00020020;");
+        let mut agdi = halted(script);
+        let mut bp = keil_bp(BP_ADDR, 1);
+        bp.cmd = 0x5678 as *mut i8;
+
+        go(&mut agdi, &mut bp);
+        assert_eq!(agdi.host.commands, vec![0x5678]);
+        assert_eq!(agdi.current_pc().unwrap(), 0x2000_0200);
+    }
+
+    #[test]
+    fn test_blocks_to_erase() {
+        let region = MemoryRegion {
+            kind: MemoryKind::Flash,
+            start: 0x0800_0000,
+            length: 0x1800,
+            blocksize: Some(0x600),
+        };
+        let mut erased = BTreeSet::new();
+
+        // 块大小不是 2 的幂，边界从区域起始地址算起
+        assert_eq!(
+            blocks_to_erase(&region, 0x0800_0700, 0x100, &mut erased),
+            vec![(0x0800_0600, 0x0800_0c00)]
+        );
+        // 低地址的段在后面到达，仍然擦除它的块，已擦过的块跳过
+        assert_eq!(
+            blocks_to_erase(&region, 0x0800_0000, 0x1000, &mut erased),
+            vec![(0x0800_0000, 0x0800_0600), (0x0800_0c00, 0x0800_1200)]
+        );
+        assert!(blocks_to_erase(&region, 0x0800_0100, 0x10, &mut erased).is_empty());
+    }
+
+    #[test]
+    fn test_mass_erase_zero_sizes() {
+        // 长度为 0 的区域被跳过，blocksize 为 0 时按默认块大小擦除
+        let script = ScriptedTransport::new()
+            .expect("vFlashErase:8000000,4000", &["OK"])
+            .expect("vFlashDone", &["OK"]);
+        let mut erase = agdi(script);
+        erase.memory_map = Some(
+            MemoryMap::parse(
+                br#"<memory-map>
+<memory type="flash" start="0x0" length="0x0"><property name="blocksize">0x400</property></memory>
+<memory type="flash" start="0x8000000" length="0x4000"><property name="blocksize">0x0</property></memory>
+</memory-map>"#,
+            )
+            .unwrap(),
+        );
+        erase.mass_erase_inner().unwrap();
+
+        let mut empty = agdi(ScriptedTransport::new());
+        empty.memory_map = Some(
+            MemoryMap::parse(
+                br#"<memory-map><memory type="flash" start="0x0" length="0x0"/></memory-map>"#,
+            )
+            .unwrap(),
+        );
+        assert!(matches!(
+            empty.mass_erase_inner(),
+            Err(Error::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_guarded_recovers_from_panic() {
+        let script = ScriptedTransport::new().expect("?", &["S05"]);
+        let mutex = Mutex::new(agdi(script));
+
+        let status = guarded(
+            || &mutex,
+            "AG_GoStep",
+            AG_NOACCESS,
+            |agdi| -> u32 {
+                agdi.gdb_client.connect().unwrap();
+                agdi.refresh_state().unwrap();
+                panic!("bug");
+            },
+        );
+        assert_eq!(status, AG_NOACCESS);
+        assert!(mutex.is_poisoned());
+
+        // 下一次调用仍然可用，连接已被断开
+        let status = guarded(
+            || &mutex,
+            "AG_Init",
+            AG_NOACCESS,
+            |agdi| {
+                assert!(!agdi.state.is_connected());
+                AG_OK
+            },
+        );
+        assert_eq!(status, AG_OK);
+        assert!(!mutex.is_poisoned());
+    }
+
+    #[test]
+    fn test_lock_released_while_running() {
+        use crate::fake_server::FakeServer;
+        use std::time::Instant;
+
+        let server = FakeServer::start();
+        let mutex = Mutex::new(Agdi::with_parts(
+            Config::default(),
+            server.transport(),
+            NullHost,
+            RecordingNotifier::default(),
+        ));
+        assert_eq!(mutex.lock().unwrap().init_features(), AG_OK);
+
+        thread::scope(|s| {
+            let go = s.spawn(|| run_go(|| &mutex, AG_GOFORBRK, 0, std::ptr::null_mut()));
+
+            // 目标运行期间其他导出函数仍能拿到锁
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                assert!(Instant::now() < deadline, "target never started running");
+                if let Ok(mut agdi) = mutex.try_lock()
+                    && agdi.state == TargetState::Running
+                {
+                    let mut addr = GADR {
+                        adr: 0x2000_0000,
+                        err_adr: 0,
+                        n_len: 4,
+                        m_space: 0,
+                    };
+                    assert_eq!(agdi.bp_info(AG_BPQUERY, std::ptr::null_mut()), 0);
+                    assert_ne!(agdi.mem_att(AG_GETMEMATT, 0, &mut addr), AG_NOACCESS);
+                    break;
+                }
+                thread::sleep(Duration::from_millis(5));
+            }
+            assert!(server.target().running);
+
+            assert_eq!(
+                run_go(|| &mutex, AG_STOPRUN, 0, std::ptr::null_mut()),
+                AG_OK
+            );
+            assert_eq!(go.join().unwrap(), AG_OK);
+        });
+        assert!(mutex.lock().unwrap().state.is_halted());
+        assert!(!server.target().running);
+    }
+}
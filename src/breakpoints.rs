@@ -0,0 +1,590 @@
+use log::{debug, warn};
+
+use crate::error::{Error, Result};
+use crate::gdb_client::{GdbClient, GdbTransport};
+use crate::memory_map::MemoryMap;
+use crate::stop_reply::WatchKind;
+
+/// Cortex-M3/M4 FPB 的指令比较器数量，读不到 FP_CTRL 时使用
+pub const DEFAULT_HW_BREAKPOINTS: usize = 6;
+
+/// Cortex-M3/M4 DWT 的比较器数量，读不到 DWT_CTRL 时使用
+pub const DEFAULT_WATCHPOINTS: usize = 4;
+
+/// FPB 控制寄存器，NUM_CODE 在 bit 14:12 和 bit 7:4
+const FP_CTRL: u32 = 0xE000_2000;
+
+/// DWT 控制寄存器，NUMCOMP 在 bit 31:28
+const DWT_CTRL: u32 = 0xE000_1000;
+
+fn fpb_num_code(fp_ctrl: u32) -> usize {
+    (((fp_ctrl >> 8) & 0x70) | ((fp_ctrl >> 4) & 0xF)) as usize
+}
+
+fn dwt_num_comp(dwt_ctrl: u32) -> usize {
+    (dwt_ctrl >> 28) as usize
+}
+
+/// ARMv7-M DWT 的 MASK 最多 15 位，即一个比较器最多覆盖 32 KiB
+pub const DEFAULT_MAX_WATCH_LEN: u32 = 1 << 15;
+
+/// Thumb 断点的 kind 参数（指令长度）
+const THUMB_BP_KIND: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointKind {
+    Software,
+    Hardware,
+}
+
+impl BreakpointKind {
+    fn z_type(self) -> u8 {
+        match self {
+            BreakpointKind::Software => 0,
+            BreakpointKind::Hardware => 1,
+        }
+    }
+
+    /// Flash 无法写入 BKPT 指令，只能用 FPB 比较器；没有内存映射时按 RAM 处理
+    pub fn for_address(addr: u32, map: &MemoryMap) -> Self {
+        if !map.regions.is_empty() && map.needs_hw_breakpoint(addr) {
+            BreakpointKind::Hardware
+        } else {
+            BreakpointKind::Software
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstalledBreakpoint {
+    pub addr: u32,
+    pub kind: BreakpointKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u32,
+    pub len: u32,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    /// acc 为 AG_Bps 的访问类型：1 = 读，2 = 写，3 = 读写
+    pub fn from_access(acc: u16, addr: u32, len: u32) -> Result<Self> {
+        let kind = match acc {
+            1 => WatchKind::Read,
+            2 => WatchKind::Write,
+            3 => WatchKind::Access,
+            _ => {
+                return Err(Error::Breakpoint {
+                    addr,
+                    reason: format!("unknown watchpoint access type {}", acc),
+                });
+            }
+        };
+        Ok(Self { addr, len, kind })
+    }
+
+    fn z_type(&self) -> u8 {
+        match self.kind {
+            WatchKind::Write => 2,
+            WatchKind::Read => 3,
+            WatchKind::Access => 4,
+        }
+    }
+
+    /// DWT 以 2 的幂为掩码匹配地址，长度必须是 2 的幂且地址按长度对齐
+    pub fn check(&self, max_len: u32) -> Result<()> {
+        let err = |why: String| Error::Breakpoint {
+            addr: self.addr,
+            reason: format!("cannot watch {} bytes: {}", self.len, why),
+        };
+
+        if !self.len.is_power_of_two() {
+            return Err(err("length must be a power of two".into()));
+        }
+        if self.len > max_len {
+            return Err(err(format!("DWT supports at most {} bytes", max_len)));
+        }
+        if !self.addr.is_multiple_of(self.len) {
+            return Err(err(format!(
+                "address must be aligned to {} bytes",
+                self.len
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.addr && addr - self.addr < self.len
+    }
+}
+
+/// 记录已经插入到目标上的断点和观察点
+pub struct BreakpointManager {
+    installed: Vec<InstalledBreakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    hw_limit: usize,
+    wp_limit: usize,
+    max_watch_len: u32,
+}
+
+impl BreakpointManager {
+    pub fn new(hw_limit: usize, wp_limit: usize, max_watch_len: u32) -> Self {
+        Self {
+            installed: Vec::new(),
+            watchpoints: Vec::new(),
+            hw_limit,
+            wp_limit,
+            max_watch_len,
+        }
+    }
+
+    pub fn hw_limit(&self) -> usize {
+        self.hw_limit
+    }
+
+    pub fn wp_limit(&self) -> usize {
+        self.wp_limit
+    }
+
+    /// 从 FP_CTRL / DWT_CTRL 读取比较器数量，读取失败时使用默认值；
+    /// 返回是否读到了其中任何一个
+    pub fn probe_limits<T: GdbTransport>(&mut self, client: &mut GdbClient<T>) -> bool {
+        let fp_ctrl = client.read_u32(FP_CTRL);
+        let dwt_ctrl = client.read_u32(DWT_CTRL);
+        let read_any = fp_ctrl.is_ok() || dwt_ctrl.is_ok();
+
+        self.hw_limit = match fp_ctrl {
+            Ok(v) => fpb_num_code(v),
+            Err(e) => {
+                warn!(
+                    "reading FP_CTRL failed, assuming {} comparators: {}",
+                    DEFAULT_HW_BREAKPOINTS, e
+                );
+                DEFAULT_HW_BREAKPOINTS
+            }
+        };
+        self.wp_limit = match dwt_ctrl {
+            Ok(v) => dwt_num_comp(v),
+            Err(e) => {
+                warn!(
+                    "reading DWT_CTRL failed, assuming {} comparators: {}",
+                    DEFAULT_WATCHPOINTS, e
+                );
+                DEFAULT_WATCHPOINTS
+            }
+        };
+        debug!(
+            "{} FPB and {} DWT comparators",
+            self.hw_limit, self.wp_limit
+        );
+        read_any
+    }
+
+    pub fn is_installed(&self, addr: u32) -> bool {
+        self.installed.iter().any(|b| b.addr == addr)
+    }
+
+    pub fn kind_at(&self, addr: u32) -> Option<BreakpointKind> {
+        self.installed
+            .iter()
+            .find(|b| b.addr == addr)
+            .map(|b| b.kind)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.installed.is_empty() && self.watchpoints.is_empty()
+    }
+
+    pub fn hw_used(&self) -> usize {
+        self.installed
+            .iter()
+            .filter(|b| b.kind == BreakpointKind::Hardware)
+            .count()
+    }
+
+    pub fn insert<T: GdbTransport>(
+        &mut self,
+        client: &mut GdbClient<T>,
+        addr: u32,
+        kind: BreakpointKind,
+    ) -> Result<()> {
+        if self.is_installed(addr) {
+            return Ok(());
+        }
+
+        if kind == BreakpointKind::Hardware && self.hw_used() >= self.hw_limit {
+            return Err(Self::fpb_exhausted(addr, self.hw_limit));
+        }
+
+        match client.insert_breakpoint(kind.z_type(), addr, THUMB_BP_KIND) {
+            Ok(()) => {}
+            // OpenOCD 在比较器用完时回复 Exx，其他错误原样返回
+            Err(Error::Server { .. }) if kind == BreakpointKind::Hardware => {
+                return Err(Self::fpb_exhausted(addr, self.hw_used()));
+            }
+            Err(e) => return Err(e),
+        }
+
+        self.installed.push(InstalledBreakpoint { addr, kind });
+        Ok(())
+    }
+
+    pub fn remove<T: GdbTransport>(&mut self, client: &mut GdbClient<T>, addr: u32) -> Result<()> {
+        let Some(idx) = self.installed.iter().position(|b| b.addr == addr) else {
+            return Ok(());
+        };
+
+        let bp = self.installed[idx];
+        client.remove_breakpoint(bp.kind.z_type(), bp.addr, THUMB_BP_KIND)?;
+        self.installed.remove(idx);
+        Ok(())
+    }
+
+    /// 让目标上的断点与 wanted 一致：多余的移除，缺少的插入
+    pub fn sync<T: GdbTransport>(
+        &mut self,
+        client: &mut GdbClient<T>,
+        wanted: &[InstalledBreakpoint],
+    ) -> Result<()> {
+        let stale: Vec<u32> = self
+            .installed
+            .iter()
+            .filter(|b| !wanted.contains(b))
+            .map(|b| b.addr)
+            .collect();
+        for addr in stale {
+            self.remove(client, addr)?;
+        }
+
+        for bp in wanted {
+            self.insert(client, bp.addr, bp.kind)?;
+        }
+        Ok(())
+    }
+
+    pub fn insert_watchpoint<T: GdbTransport>(
+        &mut self,
+        client: &mut GdbClient<T>,
+        wp: Watchpoint,
+    ) -> Result<()> {
+        if self.watchpoints.contains(&wp) {
+            return Ok(());
+        }
+
+        wp.check(self.max_watch_len)?;
+        if self.watchpoints.len() >= self.wp_limit {
+            return Err(Self::dwt_exhausted(&wp, self.wp_limit));
+        }
+
+        match client.insert_breakpoint(wp.z_type(), wp.addr, wp.len) {
+            Ok(()) => {}
+            Err(Error::Server { .. }) => {
+                return Err(Self::dwt_exhausted(&wp, self.watchpoints.len()));
+            }
+            Err(e) => return Err(e),
+        }
+
+        self.watchpoints.push(wp);
+        Ok(())
+    }
+
+    pub fn remove_watchpoint<T: GdbTransport>(
+        &mut self,
+        client: &mut GdbClient<T>,
+        wp: &Watchpoint,
+    ) -> Result<()> {
+        let Some(idx) = self.watchpoints.iter().position(|w| w == wp) else {
+            return Ok(());
+        };
+
+        client.remove_breakpoint(wp.z_type(), wp.addr, wp.len)?;
+        self.watchpoints.remove(idx);
+        Ok(())
+    }
+
+    pub fn sync_watchpoints<T: GdbTransport>(
+        &mut self,
+        client: &mut GdbClient<T>,
+        wanted: &[Watchpoint],
+    ) -> Result<()> {
+        let stale: Vec<Watchpoint> = self
+            .watchpoints
+            .iter()
+            .filter(|w| !wanted.contains(w))
+            .copied()
+            .collect();
+        for wp in &stale {
+            self.remove_watchpoint(client, wp)?;
+        }
+
+        for wp in wanted {
+            self.insert_watchpoint(client, *wp)?;
+        }
+        Ok(())
+    }
+
+    /// 停止回复中的数据地址命中了哪个观察点
+    pub fn watchpoint_at(&self, data_addr: u32) -> Option<&Watchpoint> {
+        self.watchpoints.iter().find(|w| w.contains(data_addr))
+    }
+
+    pub fn remove_all<T: GdbTransport>(&mut self, client: &mut GdbClient<T>) -> Result<()> {
+        self.sync(client, &[])?;
+        self.sync_watchpoints(client, &[])
+    }
+
+    /// 断开连接后目标上的断点状态未知，只清空记录
+    pub fn forget_all(&mut self) {
+        self.installed.clear();
+        self.watchpoints.clear();
+    }
+
+    fn fpb_exhausted(addr: u32, used: usize) -> Error {
+        let reason = match used {
+            // 比较器被其他调试器占用，或者目标没有 FPB
+            0 => "cannot set hardware breakpoint: no free FPB comparator".into(),
+            _ => format!(
+                "cannot set hardware breakpoint: all {} FPB comparators are in use",
+                used
+            ),
+        };
+        Error::Breakpoint { addr, reason }
+    }
+
+    fn dwt_exhausted(wp: &Watchpoint, used: usize) -> Error {
+        let reason = match used {
+            0 => "cannot set watchpoint: no free DWT comparator".into(),
+            _ => format!(
+                "cannot set watchpoint: all {} DWT comparators are in use",
+                used
+            ),
+        };
+        Error::Breakpoint {
+            addr: wp.addr,
+            reason,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gdb_client::MockTransport;
+
+    fn ok_replies(n: usize) -> Vec<Vec<u8>> {
+        let mut v = Vec::new();
+        for _ in 0..n {
+            v.push(vec![b'+']);
+            v.push(MockTransport::rsp_packet(b"OK"));
+        }
+        v
+    }
+
+    #[test]
+    fn test_kind_for_address() {
+        let map = MemoryMap::parse(
+            br#"<memory-map>
+  <memory type="flash" start="0x08000000" length="0x10000">
+    <property name="blocksize">0x400</property>
+  </memory>
+  <memory type="ram" start="0x20000000" length="0x5000"/>
+</memory-map>"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            BreakpointKind::for_address(0x0800_0100, &map),
+            BreakpointKind::Hardware
+        );
+        assert_eq!(
+            BreakpointKind::for_address(0x2000_0100, &map),
+            BreakpointKind::Software
+        );
+        assert_eq!(
+            BreakpointKind::for_address(0x0800_0100, &MemoryMap::default()),
+            BreakpointKind::Software
+        );
+    }
+
+    #[test]
+    fn test_probe_limits() {
+        // Cortex-M7：FP_CTRL NUM_CODE = 8，DWT_CTRL NUMCOMP = 4
+        let replies = vec![
+            vec![b'+'],
+            MockTransport::rsp_packet(b"81000000"),
+            vec![b'+'],
+            MockTransport::rsp_packet(b"00000040"),
+        ];
+        let mut client = GdbClient::new(MockTransport::new(replies, true));
+        let mut bps = BreakpointManager::new(0, 0, DEFAULT_MAX_WATCH_LEN);
+        assert!(bps.probe_limits(&mut client));
+        assert_eq!((bps.hw_limit(), bps.wp_limit()), (8, 4));
+
+        // 读取失败时使用默认值
+        let replies = vec![
+            vec![b'+'],
+            MockTransport::rsp_packet(b"E01"),
+            vec![b'+'],
+            MockTransport::rsp_packet(b"E01"),
+        ];
+        let mut client = GdbClient::new(MockTransport::new(replies, true));
+        assert!(!bps.probe_limits(&mut client));
+        assert_eq!(
+            (bps.hw_limit(), bps.wp_limit()),
+            (DEFAULT_HW_BREAKPOINTS, DEFAULT_WATCHPOINTS)
+        );
+
+        assert_eq!(fpb_num_code(0x0000_7261), 0x76);
+        assert_eq!(dwt_num_comp(0x2000_0000), 2);
+    }
+
+    #[test]
+    fn test_fpb_exhausted() {
+        let mut client = GdbClient::new(MockTransport::new(ok_replies(2), true));
+        let mut bps = BreakpointManager::new(2, DEFAULT_WATCHPOINTS, DEFAULT_MAX_WATCH_LEN);
+
+        bps.insert(&mut client, 0x0800_0000, BreakpointKind::Hardware)
+            .unwrap();
+        bps.insert(&mut client, 0x0800_0010, BreakpointKind::Hardware)
+            .unwrap();
+
+        let err = bps
+            .insert(&mut client, 0x0800_0020, BreakpointKind::Hardware)
+            .unwrap_err();
+        assert!(err.to_string().contains("FPB comparators"));
+        assert_eq!(bps.hw_used(), 2);
+    }
+
+    #[test]
+    fn test_hw_insert_errors() {
+        let mut bps = BreakpointManager::new(2, DEFAULT_WATCHPOINTS, DEFAULT_MAX_WATCH_LEN);
+
+        // 服务器回复 Exx：比较器不够
+        let replies = vec![vec![b'+'], MockTransport::rsp_packet(b"E0e")];
+        let mut client = GdbClient::new(MockTransport::new(replies, true));
+        let err = bps
+            .insert(&mut client, 0x0800_0000, BreakpointKind::Hardware)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "0x08000000: cannot set hardware breakpoint: no free FPB comparator"
+        );
+
+        // 连接断开不是比较器不够
+        let mut client = GdbClient::new(MockTransport::new(vec![], true));
+        let err = bps
+            .insert(&mut client, 0x0800_0000, BreakpointKind::Hardware)
+            .unwrap_err();
+        assert!(err.is_disconnect());
+        assert!(!err.to_string().contains("FPB"));
+        assert_eq!(bps.hw_used(), 0);
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut client = GdbClient::new(MockTransport::new(ok_replies(4), true));
+        let mut bps = BreakpointManager::new(
+            DEFAULT_HW_BREAKPOINTS,
+            DEFAULT_WATCHPOINTS,
+            DEFAULT_MAX_WATCH_LEN,
+        );
+
+        let a = InstalledBreakpoint {
+            addr: 0x0800_0000,
+            kind: BreakpointKind::Hardware,
+        };
+        let b = InstalledBreakpoint {
+            addr: 0x2000_0000,
+            kind: BreakpointKind::Software,
+        };
+
+        bps.sync(&mut client, &[a, b]).unwrap();
+        assert_eq!(bps.installed, vec![a, b]);
+
+        // 移除 a，b 保持不变
+        bps.sync(&mut client, &[b]).unwrap();
+        assert_eq!(bps.installed, vec![b]);
+
+        bps.remove_all(&mut client).unwrap();
+        assert!(bps.installed.is_empty());
+    }
+
+    #[test]
+    fn test_watchpoint_checks() {
+        let wp = Watchpoint::from_access(2, 0x2000_0010, 4).unwrap();
+        assert_eq!(wp.kind, WatchKind::Write);
+        assert!(wp.check(DEFAULT_MAX_WATCH_LEN).is_ok());
+        assert!(wp.contains(0x2000_0013));
+        assert!(!wp.contains(0x2000_0014));
+
+        // 长度不是 2 的幂
+        let wp = Watchpoint::from_access(1, 0x2000_0000, 3).unwrap();
+        assert!(wp.check(DEFAULT_MAX_WATCH_LEN).is_err());
+
+        // 未按长度对齐
+        let wp = Watchpoint::from_access(3, 0x2000_0002, 4).unwrap();
+        assert!(wp.check(DEFAULT_MAX_WATCH_LEN).is_err());
+
+        // 超过 DWT 的范围
+        let wp = Watchpoint::from_access(3, 0x2000_0000, 0x1_0000).unwrap();
+        assert!(wp.check(DEFAULT_MAX_WATCH_LEN).is_err());
+
+        assert!(Watchpoint::from_access(0, 0x2000_0000, 4).is_err());
+    }
+
+    #[test]
+    fn test_insert_watchpoints() {
+        let mut client = GdbClient::new(MockTransport::new(ok_replies(3), true));
+        let mut bps = BreakpointManager::new(DEFAULT_HW_BREAKPOINTS, 2, DEFAULT_MAX_WATCH_LEN);
+
+        let read = Watchpoint::from_access(1, 0x2000_0000, 4).unwrap();
+        let write = Watchpoint::from_access(2, 0x2000_0100, 8).unwrap();
+        let access = Watchpoint::from_access(3, 0x2000_0200, 4).unwrap();
+
+        bps.sync_watchpoints(&mut client, &[read, write]).unwrap();
+        let err = bps.insert_watchpoint(&mut client, access).unwrap_err();
+        assert!(err.to_string().contains("DWT comparators"));
+
+        assert_eq!(bps.watchpoint_at(0x2000_0104), Some(&write));
+        assert_eq!(bps.watchpoint_at(0x2000_0300), None);
+
+        bps.remove_watchpoint(&mut client, &read).unwrap();
+
+        let sent: Vec<String> = client
+            .transport()
+            .sent_packets
+            .iter()
+            .map(|p| String::from_utf8_lossy(p).into_owned())
+            .filter(|p| p.starts_with('$'))
+            .collect();
+        assert!(sent[0].starts_with("$Z3,20000000,4#"));
+        assert!(sent[1].starts_with("$Z2,20000100,8#"));
+        assert!(sent[2].starts_with("$z3,20000000,4#"));
+    }
+
+    #[test]
+    fn test_watchpoint_insert_errors() {
+        let mut bps = BreakpointManager::new(
+            DEFAULT_HW_BREAKPOINTS,
+            DEFAULT_WATCHPOINTS,
+            DEFAULT_MAX_WATCH_LEN,
+        );
+        let wp = Watchpoint::from_access(2, 0x2000_0000, 4).unwrap();
+
+        let replies = vec![vec![b'+'], MockTransport::rsp_packet(b"E0e")];
+        let mut client = GdbClient::new(MockTransport::new(replies, true));
+        let err = bps.insert_watchpoint(&mut client, wp).unwrap_err();
+        assert!(err.to_string().contains("no free DWT comparator"));
+
+        let mut client = GdbClient::new(MockTransport::new(vec![], true));
+        let err = bps.insert_watchpoint(&mut client, wp).unwrap_err();
+        assert!(err.is_disconnect());
+
+        let replies = vec![vec![b'+'], MockTransport::rsp_packet(b"")];
+        let mut client = GdbClient::new(MockTransport::new(replies, true));
+        let err = bps.insert_watchpoint(&mut client, wp).unwrap_err();
+        assert!(matches!(err, Error::Unsupported { .. }));
+    }
+}
@@ -0,0 +1,430 @@
+use std::io;
+use std::path::PathBuf;
+
+use log::LevelFilter;
+
+use crate::error::{Error, Result};
+use crate::option_bytes::{OptionByte, OptionBytes, parse_u32};
+use crate::protection::{Protection, ProtectionCheck};
+
+/// 配置文件路径的环境变量，未设置时使用当前目录（Keil 工程目录）下的 openocd_agdi.ini
+pub const CONFIG_ENV: &str = "OPENOCD_AGDI_CONFIG";
+pub const CONFIG_FILE: &str = "openocd_agdi.ini";
+
+/// 调试会话或下载结束时如何处理目标
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitAction {
+    /// 发送 `D` 后断开
+    Detach,
+    /// 让目标继续运行后断开
+    Resume,
+    /// 发送 `k` 后断开
+    Kill,
+    /// 直接关闭连接
+    Drop,
+}
+
+impl ExitAction {
+    fn parse(section: &str, key: &str, value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "detach" => Ok(ExitAction::Detach),
+            "resume" | "run" => Ok(ExitAction::Resume),
+            "kill" | "reset" => Ok(ExitAction::Kill),
+            "drop" | "none" => Ok(ExitAction::Drop),
+            _ => Err(invalid(section, key, value)),
+        }
+    }
+}
+
+/// 下载完成后对目标的操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostDownload {
+    None,
+    /// `monitor reset run`
+    ResetRun,
+    /// `monitor reset halt`
+    ResetHalt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub halt_on_connect: bool,
+    pub exit_action: ExitAction,
+    /// 下载完成后断开的方式
+    pub flash_exit_action: ExitAction,
+    pub post_download: PostDownload,
+    /// 擦除前 / flash_done 后依次执行的 monitor 命令
+    pub pre_flash_cmds: Vec<String>,
+    pub post_flash_cmds: Vec<String>,
+    /// 整片擦除使用的 monitor 命令，未设置时逐块擦除所有 flash 区域
+    pub mass_erase_cmd: Option<String>,
+    pub option_bytes: OptionBytes,
+    pub protection: Protection,
+    /// 镜像下载到 RAM 后按向量表设置 SP / PC 并运行
+    pub ram_run: bool,
+    /// 向量表地址，未设置时使用最低的 RAM 地址
+    pub ram_vector_table: Option<u32>,
+    /// 记录与服务器之间的全部数据，用于复现问题
+    pub record: Option<PathBuf>,
+    /// 日志文件，未设置时不记录
+    pub log_file: Option<PathBuf>,
+    pub log_level: LevelFilter,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: "localhost".into(),
+            port: 3333,
+            halt_on_connect: true,
+            exit_action: ExitAction::Detach,
+            flash_exit_action: ExitAction::Drop,
+            post_download: PostDownload::None,
+            pre_flash_cmds: Vec::new(),
+            post_flash_cmds: Vec::new(),
+            mass_erase_cmd: None,
+            option_bytes: OptionBytes::default(),
+            protection: Protection::default(),
+            ram_run: false,
+            ram_vector_table: None,
+            record: None,
+            log_file: None,
+            log_level: LevelFilter::Info,
+        }
+    }
+}
+
+/// 简单的 ini：`[section]`、`key = value`，`;` / `#` 开头为注释，同一个 key 可以重复出现
+#[derive(Debug, Default)]
+pub struct Ini {
+    entries: Vec<(String, String, String)>,
+}
+
+impl Ini {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        let mut section = String::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.trim().to_ascii_lowercase();
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(Error::Config {
+                    location: format!("line {}", n + 1),
+                    message: format!("expected `key = value`: {}", line),
+                });
+            };
+            entries.push((
+                section.clone(),
+                key.trim().to_ascii_lowercase(),
+                value.trim().to_string(),
+            ));
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// 最后一次出现的值
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.get_all(section, key).last().copied()
+    }
+
+    pub fn get_all(&self, section: &str, key: &str) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(s, k, _)| s == section && k == key)
+            .map(|(_, _, v)| v.as_str())
+            .collect()
+    }
+}
+
+fn invalid(section: &str, key: &str, value: &str) -> Error {
+    Error::Config {
+        location: format!("[{}] {}", section, key),
+        message: format!("invalid value `{}`", value),
+    }
+}
+
+fn option_list(ini: &Ini, key: &str) -> Result<Vec<OptionByte>> {
+    ini.get_all("option_bytes", key)
+        .into_iter()
+        .map(|v| OptionByte::parse(v).ok_or_else(|| invalid("option_bytes", key, v)))
+        .collect()
+}
+
+fn parse_bool(section: &str, key: &str, value: &str) -> Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(invalid(section, key, value)),
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self> {
+        let ini = Ini::parse(text)?;
+        let mut cfg = Config::default();
+
+        if let Some(v) = ini.get("server", "host") {
+            cfg.host = v.to_string();
+        }
+        if let Some(v) = ini.get("server", "port") {
+            cfg.port = v.parse().map_err(|_| invalid("server", "port", v))?;
+        }
+
+        if let Some(v) = ini.get("session", "halt_on_connect") {
+            cfg.halt_on_connect = parse_bool("session", "halt_on_connect", v)?;
+        }
+        if let Some(v) = ini.get("session", "on_exit") {
+            cfg.exit_action = ExitAction::parse("session", "on_exit", v)?;
+        }
+
+        if let Some(v) = ini.get("flash", "on_exit") {
+            cfg.flash_exit_action = ExitAction::parse("flash", "on_exit", v)?;
+        }
+        if let Some(v) = ini.get("flash", "after_download") {
+            cfg.post_download = match v.to_ascii_lowercase().as_str() {
+                "none" => PostDownload::None,
+                "reset-run" | "reset_run" => PostDownload::ResetRun,
+                "reset-halt" | "reset_halt" => PostDownload::ResetHalt,
+                _ => return Err(invalid("flash", "after_download", v)),
+            };
+        }
+        cfg.pre_flash_cmds = ini
+            .get_all("flash", "before_erase")
+            .into_iter()
+            .map(String::from)
+            .collect();
+        cfg.post_flash_cmds = ini
+            .get_all("flash", "after_done")
+            .into_iter()
+            .map(String::from)
+            .collect();
+        cfg.mass_erase_cmd = ini.get("flash", "mass_erase").map(String::from);
+        if let Some(v) = ini.get("flash", "ram_run") {
+            cfg.ram_run = parse_bool("flash", "ram_run", v)?;
+        }
+        if let Some(v) = ini.get("flash", "ram_vector_table") {
+            cfg.ram_vector_table =
+                Some(parse_u32(v).ok_or_else(|| invalid("flash", "ram_vector_table", v))?);
+        }
+
+        cfg.record = ini.get("log", "record").map(PathBuf::from);
+        cfg.log_file = ini.get("log", "file").map(PathBuf::from);
+        if let Some(v) = ini.get("log", "level") {
+            cfg.log_level = v.parse().map_err(|_| invalid("log", "level", v))?;
+        }
+
+        let ob = &mut cfg.option_bytes;
+        ob.family = ini
+            .get("option_bytes", "family")
+            .map(str::to_ascii_lowercase);
+        ob.apply = ini
+            .get_all("option_bytes", "apply")
+            .into_iter()
+            .map(String::from)
+            .collect();
+        ob.confirm = ini
+            .get_all("option_bytes", "confirm")
+            .into_iter()
+            .map(String::from)
+            .collect();
+        ob.write = option_list(&ini, "write")?;
+        ob.otp = option_list(&ini, "otp")?;
+        ob.verify = option_list(&ini, "verify")?;
+        if let Some(v) = ini.get("option_bytes", "allow_irreversible") {
+            ob.allow_irreversible = parse_bool("option_bytes", "allow_irreversible", v)?;
+        }
+
+        let prot = &mut cfg.protection;
+        if let Some(v) = ini.get("protection", "check") {
+            let reg = OptionByte::parse(v).ok_or_else(|| invalid("protection", "check", v))?;
+            prot.check = Some(ProtectionCheck::Register(reg));
+        }
+        if let Some(cmd) = ini.get("protection", "check_cmd") {
+            let Some(pattern) = ini.get("protection", "check_pattern") else {
+                return Err(invalid("protection", "check_pattern", ""));
+            };
+            prot.check = Some(ProtectionCheck::Monitor {
+                cmd: cmd.to_string(),
+                pattern: pattern.to_string(),
+            });
+        }
+        prot.unlock = ini
+            .get_all("protection", "unlock")
+            .into_iter()
+            .map(String::from)
+            .collect();
+        if let Some(v) = ini.get("protection", "allow_unlock") {
+            prot.allow_unlock = parse_bool("protection", "allow_unlock", v)?;
+        }
+
+        Ok(cfg)
+    }
+
+    pub fn path() -> PathBuf {
+        std::env::var_os(CONFIG_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(CONFIG_FILE))
+    }
+
+    /// 配置文件不存在时使用默认值
+    pub fn load() -> Result<Self> {
+        let path = Self::path();
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text).map_err(|e| Error::Config {
+                location: path.display().to_string(),
+                message: e.to_string(),
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::Config {
+                location: path.display().to_string(),
+                message: e.to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_parse() {
+        let cfg = Config::parse(
+            r#"
+; 注释
+[server]
+host = 192.168.1.10
+port = 4444
+
+[session]
+halt_on_connect = no
+on_exit = resume
+
+[flash]
+on_exit = kill
+after_download = reset-run
+before_erase = reset halt
+before_erase = adapter speed 4000
+after_done = my_verify_proc 0x08000000
+mass_erase = stm32f1x mass_erase 0
+ram_run = yes
+ram_vector_table = 0x20000000
+
+[log]
+record = logs/gdb.rec
+file = logs/openocd_agdi.log
+level = Debug
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(cfg.host, "192.168.1.10");
+        assert_eq!(cfg.port, 4444);
+        assert!(!cfg.halt_on_connect);
+        assert_eq!(cfg.exit_action, ExitAction::Resume);
+        assert_eq!(cfg.flash_exit_action, ExitAction::Kill);
+        assert_eq!(cfg.post_download, PostDownload::ResetRun);
+        assert_eq!(cfg.pre_flash_cmds, vec!["reset halt", "adapter speed 4000"]);
+        assert_eq!(cfg.post_flash_cmds, vec!["my_verify_proc 0x08000000"]);
+        assert_eq!(cfg.mass_erase_cmd.as_deref(), Some("stm32f1x mass_erase 0"));
+        assert!(cfg.ram_run);
+        assert_eq!(cfg.ram_vector_table, Some(0x2000_0000));
+        assert_eq!(cfg.record, Some(PathBuf::from("logs/gdb.rec")));
+        assert_eq!(cfg.log_file, Some(PathBuf::from("logs/openocd_agdi.log")));
+        assert_eq!(cfg.log_level, LevelFilter::Debug);
+    }
+
+    #[test]
+    fn test_option_bytes() {
+        let cfg = Config::parse(
+            r#"
+[option_bytes]
+family = STM32F4x
+apply = stm32f4x options_write 0 0xAA 0x0FFF
+apply = my_option_proc
+confirm = my_option_proc
+write = 0x40023C14 0x0FFFAAEC
+verify = 0x1FFFC000 0xAAEC 0xFFFF
+"#,
+        )
+        .unwrap();
+
+        let ob = &cfg.option_bytes;
+        assert_eq!(ob.family.as_deref(), Some("stm32f4x"));
+        assert_eq!(ob.apply.len(), 2);
+        assert_eq!(ob.confirm, vec!["my_option_proc"]);
+        assert_eq!(ob.write[0].addr, 0x4002_3c14);
+        assert_eq!(ob.verify[0].mask, 0xffff);
+        assert!(ob.otp.is_empty());
+        assert!(!ob.allow_irreversible);
+
+        assert!(
+            Config::parse(
+                "[option_bytes]
+verify = 0x1FFFC000
+"
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_protection() {
+        let cfg = Config::parse(
+            r#"
+[protection]
+check_cmd = stm32f1x options_read 0
+check_pattern = read protection: on
+unlock = stm32f1x unlock 0
+unlock = reset halt
+allow_unlock = yes
+"#,
+        )
+        .unwrap();
+
+        let prot = &cfg.protection;
+        assert!(matches!(
+            prot.check,
+            Some(ProtectionCheck::Monitor { ref pattern, .. }) if pattern == "read protection: on"
+        ));
+        assert_eq!(prot.unlock, vec!["stm32f1x unlock 0", "reset halt"]);
+        assert!(prot.allow_unlock);
+
+        assert!(Config::parse("[protection]\ncheck_cmd = x\n").is_err());
+    }
+
+    #[test]
+    fn test_repeated_keys() {
+        let ini = Ini::parse("[a]\nx = 1\nx = 2\n[b]\nx = 3\n").unwrap();
+        assert_eq!(ini.get_all("a", "x"), vec!["1", "2"]);
+        assert_eq!(ini.get("a", "x"), Some("2"));
+        assert_eq!(ini.get("b", "x"), Some("3"));
+        assert_eq!(ini.get("c", "x"), None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Config::parse("[server]\nport = abc\n").is_err());
+        assert!(Config::parse("[session]\non_exit = explode\n").is_err());
+        assert!(Config::parse("[flash]\nafter_download = later\n").is_err());
+        assert!(Config::parse("[session]\nno equals sign\n").is_err());
+        assert!(Config::parse("[log]\nlevel = verbose\n").is_err());
+    }
+}
@@ -0,0 +1,315 @@
+//! 驱动内部统一的错误类型，AGDI 层据此生成提示和返回码
+
+use std::fmt;
+use std::io;
+
+use crate::agdi_consts::{AG_CANTMAP, AG_INVALOP, AG_NOACCESS, AG_RDFAILED, AG_RO, AG_WRFAILED};
+use crate::target_state::TargetState;
+
+/// 出错的命令是读还是写，决定返回给 uVision 的错误码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpKind {
+    Read,
+    Write,
+    Other,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// 与服务器之间的读写失败，op 为当时发送的命令
+    Transport { op: String, source: io::Error },
+    /// 等待回复超时
+    Timeout { op: String },
+    /// 服务器对包回复了 `-`
+    Nack { op: String },
+    /// 收到的包校验和不正确
+    Checksum {
+        op: String,
+        expected: u8,
+        actual: u8,
+    },
+    /// 回复的格式不符合预期
+    Malformed {
+        op: String,
+        kind: OpKind,
+        reply: String,
+    },
+    /// 服务器回复 `Exx`
+    Server {
+        op: String,
+        kind: OpKind,
+        addr: Option<u32>,
+        code: u8,
+    },
+    /// 空回复：服务器不支持该命令
+    Unsupported { op: String },
+    /// monitor 命令失败，output 为命令的输出
+    Monitor {
+        cmd: String,
+        reply: String,
+        output: String,
+    },
+    /// 当前目标状态下不允许的操作
+    TargetState { op: String, state: TargetState },
+    /// 地址范围不在内存映射的同一个区域中
+    Unmapped { addr: u32, len: u32 },
+    /// 只读区域
+    ReadOnly { addr: u32 },
+    /// 断点 / 观察点资源不足或参数无效
+    Breakpoint { addr: u32, reason: String },
+    /// 回读校验失败
+    Verify {
+        addr: u32,
+        read: u32,
+        expected: u32,
+        mask: u32,
+    },
+    /// 需要在配置中确认才能执行的操作
+    Refused { action: String, hint: String },
+    /// 配置文件错误，location 为 `[section] key`、行号或文件名
+    Config { location: String, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// 按回复生成错误：空回复为不支持，`Exx` 为服务器错误，其余为格式错误
+    pub fn from_reply(op: &str, kind: OpKind, addr: Option<u32>, reply: &[u8]) -> Self {
+        let code = match reply {
+            [b'E', hi, lo] => std::str::from_utf8(&[*hi, *lo])
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok()),
+            _ => None,
+        };
+        match (reply, code) {
+            ([], _) => Error::Unsupported { op: op.into() },
+            (_, Some(code)) => Error::Server {
+                op: op.into(),
+                kind,
+                addr,
+                code,
+            },
+            _ => Error::Malformed {
+                op: op.into(),
+                kind,
+                reply: String::from_utf8_lossy(reply).into_owned(),
+            },
+        }
+    }
+
+    /// 低层的错误不知道当时在执行哪个命令，由上层补充
+    pub fn during(mut self, cmd: &str) -> Self {
+        match &mut self {
+            Error::Transport { op, .. }
+            | Error::Timeout { op }
+            | Error::Nack { op }
+            | Error::Checksum { op, .. }
+                if op.is_empty() =>
+            {
+                *op = cmd.to_string();
+            }
+            _ => {}
+        }
+        self
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Error::Timeout { .. })
+    }
+
+    /// 连接已经被服务器关闭
+    pub fn is_disconnect(&self) -> bool {
+        matches!(
+            self,
+            Error::Transport { source, .. } if matches!(
+                source.kind(),
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+            )
+        )
+    }
+
+    /// 返回给 uVision 的 AG_* 错误码
+    pub fn ag_code(&self) -> u32 {
+        match self {
+            Error::Server {
+                kind: OpKind::Read, ..
+            }
+            | Error::Malformed {
+                kind: OpKind::Read, ..
+            } => AG_RDFAILED,
+            Error::Server { .. } | Error::Monitor { .. } | Error::Verify { .. } => AG_WRFAILED,
+            Error::Unmapped { .. } => AG_CANTMAP,
+            Error::ReadOnly { .. } => AG_RO,
+            Error::Unsupported { .. }
+            | Error::Breakpoint { .. }
+            | Error::Refused { .. }
+            | Error::Config { .. } => AG_INVALOP,
+            _ => AG_NOACCESS,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport { op, source } if op.is_empty() => write!(f, "{}", source),
+            Error::Transport { op, source } => write!(f, "{}: {}", op, source),
+            Error::Timeout { op } => write!(f, "timed out waiting for reply to {}", op),
+            Error::Nack { op } => write!(f, "server rejected {} (NACK)", op),
+            Error::Checksum {
+                op,
+                expected,
+                actual,
+            } if op.is_empty() => write!(
+                f,
+                "bad packet checksum: expected {:02x}, got {:02x}",
+                expected, actual
+            ),
+            Error::Checksum {
+                op,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "bad checksum in reply to {}: expected {:02x}, got {:02x}",
+                op, expected, actual
+            ),
+            Error::Malformed { op, reply, .. } => write!(f, "malformed {} reply: {}", op, reply),
+            Error::Server {
+                op,
+                addr: Some(addr),
+                code,
+                ..
+            } => write!(
+                f,
+                "{} failed at {:#010x}: server replied E{:02x}",
+                op, addr, code
+            ),
+            Error::Server {
+                op,
+                addr: None,
+                code,
+                ..
+            } => write!(f, "{} failed: server replied E{:02x}", op, code),
+            Error::Unsupported { op } => write!(f, "{} not supported by server", op),
+            Error::Monitor { cmd, reply, output } => {
+                write!(
+                    f,
+                    "monitor {} failed ({}): {}",
+                    cmd,
+                    reply,
+                    output.trim_end()
+                )
+            }
+            Error::TargetState { op, state } => {
+                write!(f, "{} not allowed while target is {:?}", op, state)
+            }
+            Error::Unmapped { addr, len } => write!(
+                f,
+                "{:#010x}..{:#010x} is not in a single memory region",
+                addr,
+                *addr as u64 + *len as u64
+            ),
+            Error::ReadOnly { addr } => write!(f, "{:#010x} is read-only memory", addr),
+            Error::Breakpoint { addr, reason } => write!(f, "{:#010x}: {}", addr, reason),
+            Error::Verify {
+                addr,
+                read,
+                expected,
+                mask,
+            } => write!(
+                f,
+                "verify failed at {:#010x}: read {:#010x}, expected {:#010x} (mask {:#010x})",
+                addr, read, expected, mask
+            ),
+            Error::Refused { action, hint } => write!(f, "refusing {}; {}", action, hint),
+            Error::Config { location, message } => write!(f, "{}: {}", location, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                Error::Timeout { op: String::new() }
+            }
+            _ => Error::Transport {
+                op: String::new(),
+                source: e,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_reply() {
+        let e = Error::from_reply("erase", OpKind::Write, Some(0x0802_0000), b"E04");
+        assert_eq!(
+            e.to_string(),
+            "erase failed at 0x08020000: server replied E04"
+        );
+        assert_eq!(e.ag_code(), AG_WRFAILED);
+
+        let e = Error::from_reply("read memory", OpKind::Read, Some(0x2000_0000), b"E0e");
+        assert_eq!(e.ag_code(), AG_RDFAILED);
+        assert!(e.to_string().contains("E0e"));
+
+        // 错误码由命令类型决定，与名称无关
+        let e = Error::from_reply("memory-map", OpKind::Read, None, b"E01");
+        assert_eq!(e.ag_code(), AG_RDFAILED);
+        let e = Error::from_reply("read-out protection", OpKind::Write, None, b"E01");
+        assert_eq!(e.ag_code(), AG_WRFAILED);
+        let e = Error::from_reply("read memory", OpKind::Read, None, b"zz");
+        assert_eq!(e.ag_code(), AG_RDFAILED);
+        let e = Error::from_reply("qSupported", OpKind::Other, None, b"zz");
+        assert_eq!(e.ag_code(), AG_NOACCESS);
+
+        assert!(matches!(
+            Error::from_reply("Z2", OpKind::Other, None, b""),
+            Error::Unsupported { .. }
+        ));
+        assert!(matches!(
+            Error::from_reply("vFlashDone", OpKind::Write, None, b"what"),
+            Error::Malformed { .. }
+        ));
+    }
+
+    #[test]
+    fn test_io_error_context() {
+        let e = Error::from(io::Error::new(io::ErrorKind::TimedOut, "t")).during("vFlashDone");
+        assert!(e.is_timeout());
+        assert_eq!(e.to_string(), "timed out waiting for reply to vFlashDone");
+
+        let e = Error::from(io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
+        assert!(e.is_disconnect());
+        assert_eq!(e.ag_code(), AG_NOACCESS);
+        // 已有的上下文不会被覆盖
+        assert_eq!(e.during("g").during("m0,4").to_string(), "g: eof");
+
+        let e = Error::Checksum {
+            op: String::new(),
+            expected: 0,
+            actual: 0x9a,
+        };
+        assert_eq!(
+            e.during("vFlashDone").to_string(),
+            "bad checksum in reply to vFlashDone: expected 00, got 9a"
+        );
+    }
+}
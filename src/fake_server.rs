@@ -0,0 +1,661 @@
+//! 测试用的 GDB 服务器：在 localhost 上监听，模拟 OpenOCD 和一块小 MCU
+//! （按扇区擦除的 flash、RAM、寄存器、memory-map），可以注入错误
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::gdb_client::TcpTransport;
+use crate::stop_reply::hex_decode;
+
+pub const FLASH_BASE: u32 = 0x0800_0000;
+pub const FLASH_SIZE: u32 = 0x8000;
+pub const SECTOR_SIZE: u32 = 0x400;
+pub const RAM_BASE: u32 = 0x2000_0000;
+pub const RAM_SIZE: u32 = 0x2000;
+/// OTP 区域，通过 vFlashWrite 写入，每个字节只能写一次
+pub const OTP_BASE: u32 = 0x1FFF_7800;
+pub const OTP_SIZE: u32 = 0x210;
+
+/// 注入到匹配前缀的包上的错误
+#[derive(Debug, Clone)]
+pub enum Fault {
+    /// 回复 `-`，不处理这个包
+    Nack,
+    /// 用给定内容代替正常回复，例如 `E01`
+    Reply(&'static str),
+    /// 回复前等待
+    Delay(Duration),
+    /// 不回复，直接关闭连接
+    Drop,
+}
+
+#[derive(Debug)]
+struct FaultRule {
+    prefix: String,
+    fault: Fault,
+    remaining: usize,
+}
+
+/// 被模拟的目标，测试可以在服务器运行时读写
+#[derive(Debug)]
+pub struct SimTarget {
+    pub flash: Vec<u8>,
+    pub ram: Vec<u8>,
+    /// 其他地址（外设寄存器、选项字节等），只有写过或预置的才能读
+    pub periph: BTreeMap<u32, u8>,
+    pub regs: [u32; 17],
+    pub running: bool,
+    pub breakpoints: Vec<(u8, u32)>,
+    /// monitor 命令 -> (输出, 是否成功)
+    pub monitor: BTreeMap<String, (String, bool)>,
+    /// qXfer:memory-map:read 返回的 XML
+    pub memory_map: String,
+    /// 读保护：flash 的擦除、写入和读取都回复错误，`stm32f1x unlock 0` 解除
+    pub protected: bool,
+    /// 收到的所有包，便于断言
+    pub log: Vec<String>,
+    pending: Vec<(u32, Vec<u8>)>,
+    faults: Vec<FaultRule>,
+}
+
+impl SimTarget {
+    pub fn new() -> Self {
+        Self {
+            flash: vec![0xFF; FLASH_SIZE as usize],
+            ram: vec![0; RAM_SIZE as usize],
+            periph: BTreeMap::new(),
+            regs: [0; 17],
+            running: false,
+            breakpoints: Vec::new(),
+            monitor: BTreeMap::new(),
+            memory_map: Self::memory_map_xml(),
+            protected: false,
+            log: Vec::new(),
+            pending: Vec::new(),
+            faults: Vec::new(),
+        }
+    }
+
+    /// 前 times 个以 prefix 开头的包触发 fault
+    pub fn inject(&mut self, prefix: &str, fault: Fault, times: usize) {
+        self.faults.push(FaultRule {
+            prefix: prefix.to_string(),
+            fault,
+            remaining: times,
+        });
+    }
+
+    pub fn memory_map_xml() -> String {
+        format!(
+            r#"<?xml version="1.0"?>
+<!DOCTYPE memory-map PUBLIC "+//IDN gnu.org//DTD GDB Memory Map V1.0//EN" "http://sourceware.org/gdb/gdb-memory-map.dtd">
+<memory-map>
+<memory type="flash" start="{:#x}" length="{:#x}"><property name="blocksize">{:#x}</property></memory>
+<memory type="ram" start="{:#x}" length="{:#x}"/>
+</memory-map>
+"#,
+            FLASH_BASE, FLASH_SIZE, SECTOR_SIZE, RAM_BASE, RAM_SIZE
+        )
+    }
+
+    fn take_fault(&mut self, packet: &[u8]) -> Option<Fault> {
+        let rule = self
+            .faults
+            .iter_mut()
+            .find(|r| r.remaining > 0 && packet.starts_with(r.prefix.as_bytes()))?;
+        rule.remaining -= 1;
+        Some(rule.fault.clone())
+    }
+
+    fn flash_offset(addr: u32, len: u32) -> Option<usize> {
+        let end = addr as u64 + len as u64;
+        (addr >= FLASH_BASE && end <= (FLASH_BASE + FLASH_SIZE) as u64)
+            .then(|| (addr - FLASH_BASE) as usize)
+    }
+
+    fn ram_offset(addr: u32, len: u32) -> Option<usize> {
+        let end = addr as u64 + len as u64;
+        (addr >= RAM_BASE && end <= (RAM_BASE + RAM_SIZE) as u64)
+            .then(|| (addr - RAM_BASE) as usize)
+    }
+
+    pub fn read(&self, addr: u32, len: u32) -> Option<Vec<u8>> {
+        if let Some(off) = Self::flash_offset(addr, len) {
+            return (!self.protected).then(|| self.flash[off..off + len as usize].to_vec());
+        }
+        if let Some(off) = Self::ram_offset(addr, len) {
+            return Some(self.ram[off..off + len as usize].to_vec());
+        }
+        (0..len)
+            .map(|i| self.periph.get(&(addr + i)).copied())
+            .collect()
+    }
+
+    /// 普通内存写入，flash 只能通过 vFlash* 写
+    pub fn write(&mut self, addr: u32, data: &[u8]) -> bool {
+        if Self::flash_offset(addr, data.len() as u32).is_some() {
+            return false;
+        }
+        if let Some(off) = Self::ram_offset(addr, data.len() as u32) {
+            self.ram[off..off + data.len()].copy_from_slice(data);
+            return true;
+        }
+        for (i, b) in data.iter().enumerate() {
+            self.periph.insert(addr + i as u32, *b);
+        }
+        true
+    }
+
+    fn flash_erase(&mut self, addr: u32, len: u32) -> bool {
+        if self.protected || !addr.is_multiple_of(SECTOR_SIZE) || !len.is_multiple_of(SECTOR_SIZE) {
+            return false;
+        }
+        let Some(off) = Self::flash_offset(addr, len) else {
+            return false;
+        };
+        self.flash[off..off + len as usize].fill(0xFF);
+        true
+    }
+
+    fn otp_write(&mut self, addr: u32, data: &[u8]) -> bool {
+        let end = addr as u64 + data.len() as u64;
+        let inside = addr >= OTP_BASE && end <= (OTP_BASE + OTP_SIZE) as u64;
+        let addrs = addr..addr + data.len() as u32;
+        if !inside || addrs.clone().any(|a| self.periph.contains_key(&a)) {
+            return false;
+        }
+        for (a, b) in addrs.zip(data) {
+            self.periph.insert(a, *b);
+        }
+        true
+    }
+
+    /// 和真实 flash 一样，只能写已经擦除的字节
+    fn flash_done(&mut self) -> bool {
+        let pending = std::mem::take(&mut self.pending);
+        for (addr, data) in pending {
+            let off = (addr - FLASH_BASE) as usize;
+            let dst = &mut self.flash[off..off + data.len()];
+            if dst.iter().any(|b| *b != 0xFF) {
+                return false;
+            }
+            dst.copy_from_slice(&data);
+        }
+        true
+    }
+
+    fn stop_reply(&self) -> Vec<u8> {
+        b"S05".to_vec()
+    }
+
+    /// 处理一个包，返回要发送的回复
+    fn handle(&mut self, packet: &[u8]) -> Reply {
+        let text = String::from_utf8_lossy(packet).into_owned();
+
+        if let Some(rest) = text.strip_prefix("qXfer:memory-map:read::") {
+            let Some((off, len)) = parse_pair(rest, ',') else {
+                return Reply::error();
+            };
+            let xml = self.memory_map.as_bytes();
+            let off = (off as usize).min(xml.len());
+            let end = (off + len as usize).min(xml.len());
+            let mut out = vec![if end < xml.len() { b'm' } else { b'l' }];
+            out.extend_from_slice(&xml[off..end]);
+            return Reply::Packet(out);
+        }
+        if text.starts_with("qSupported") {
+            return Reply::text("PacketSize=4000;qXfer:memory-map:read+;swbreak+;hwbreak+");
+        }
+        if let Some(hex) = text.strip_prefix("qRcmd,") {
+            let Ok(cmd) = hex_decode(hex.as_bytes()) else {
+                return Reply::error();
+            };
+            return self.run_monitor(&String::from_utf8_lossy(&cmd));
+        }
+        if let Some(rest) = text.strip_prefix("vFlashErase:") {
+            let ok = parse_pair(rest, ',').is_some_and(|(a, l)| self.flash_erase(a, l));
+            return Reply::status(ok);
+        }
+        if packet.starts_with(b"vFlashWrite:") {
+            let rest = &packet[b"vFlashWrite:".len()..];
+            let Some(colon) = rest.iter().position(|b| *b == b':') else {
+                return Reply::error();
+            };
+            let addr = parse_hex(&String::from_utf8_lossy(&rest[..colon]));
+            let data = unescape(&rest[colon + 1..]);
+            return match addr {
+                Some(_) if self.protected => Reply::error(),
+                Some(a) if Self::flash_offset(a, data.len() as u32).is_some() => {
+                    self.pending.push((a, data));
+                    Reply::ok()
+                }
+                Some(a) => Reply::status(self.otp_write(a, &data)),
+                _ => Reply::error(),
+            };
+        }
+        if text == "vFlashDone" {
+            let ok = self.flash_done();
+            return Reply::status(ok);
+        }
+
+        match packet.first() {
+            Some(b'?') => Reply::Packet(self.stop_reply()),
+            Some(b'm') => match parse_pair(&text[1..], ',').and_then(|(a, l)| self.read(a, l)) {
+                Some(data) => Reply::Packet(hex_encode(&data).into_bytes()),
+                None => Reply::error(),
+            },
+            Some(b'M') => {
+                let ok = text[1..]
+                    .split_once(':')
+                    .and_then(|(range, hex)| {
+                        let (addr, _) = parse_pair(range, ',')?;
+                        Some((addr, hex_decode(hex.as_bytes()).ok()?))
+                    })
+                    .is_some_and(|(addr, data)| self.write(addr, &data));
+                Reply::status(ok)
+            }
+            Some(b'X') => {
+                let Some(colon) = packet.iter().position(|b| *b == b':') else {
+                    return Reply::error();
+                };
+                let data = unescape(&packet[colon + 1..]);
+                let ok = parse_pair(&String::from_utf8_lossy(&packet[1..colon]), ',')
+                    .is_some_and(|(addr, _)| self.write(addr, &data));
+                Reply::status(ok)
+            }
+            Some(b'p') => match parse_hex(&text[1..]).and_then(|n| self.regs.get(n as usize)) {
+                Some(v) => Reply::Packet(hex_encode(&v.to_le_bytes()).into_bytes()),
+                None => Reply::error(),
+            },
+            Some(b'P') => {
+                let set = text[1..].split_once('=').and_then(|(n, v)| {
+                    let n = parse_hex(n)? as usize;
+                    let bytes = hex_decode(v.as_bytes()).ok()?;
+                    let value = u32::from_le_bytes(bytes.try_into().ok()?);
+                    Some((n, value))
+                });
+                match set {
+                    Some((n, v)) if n < self.regs.len() => {
+                        self.regs[n] = v;
+                        Reply::ok()
+                    }
+                    _ => Reply::error(),
+                }
+            }
+            Some(b'Z' | b'z') => {
+                let parsed = text[1..].split(',').collect::<Vec<_>>();
+                let (Some(t), Some(addr)) = (
+                    parsed.first().and_then(|t| t.parse::<u8>().ok()),
+                    parsed.get(1).and_then(|a| parse_hex(a)),
+                ) else {
+                    return Reply::error();
+                };
+                if packet[0] == b'Z' {
+                    self.breakpoints.push((t, addr));
+                } else {
+                    self.breakpoints.retain(|b| *b != (t, addr));
+                }
+                Reply::ok()
+            }
+            Some(b's') => Reply::Packet(self.stop_reply()),
+            Some(b'c') => {
+                // 有断点时立即停在最低的断点上，否则一直运行到 Ctrl-C
+                match self.breakpoints.iter().map(|b| b.1).min() {
+                    Some(addr) => {
+                        self.regs[15] = addr;
+                        Reply::text("T05hwbreak:;")
+                    }
+                    None => {
+                        self.running = true;
+                        Reply::None
+                    }
+                }
+            }
+            Some(b'D') => Reply::Close(Some(b"OK".to_vec())),
+            Some(b'k') => Reply::Close(None),
+            _ => Reply::Packet(Vec::new()),
+        }
+    }
+
+    fn run_monitor(&mut self, cmd: &str) -> Reply {
+        let (output, ok) = match self.monitor.get(cmd) {
+            Some(r) => r.clone(),
+            None => match cmd {
+                "reset halt" | "reset init" => {
+                    self.running = false;
+                    (String::new(), true)
+                }
+                "reset run" | "reset" => {
+                    self.running = true;
+                    (String::new(), true)
+                }
+                "stm32f1x options_read 0" => {
+                    let rdp = if self.protected { "on" } else { "off" };
+                    (format!("read protection: {}\n", rdp), true)
+                }
+                // 解除读保护时整片擦除
+                "stm32f1x unlock 0" => {
+                    self.protected = false;
+                    self.flash.fill(0xFF);
+                    (String::new(), true)
+                }
+                _ => (format!("invalid command name \"{}\"\n", cmd), false),
+            },
+        };
+
+        let mut packets = Vec::new();
+        if !output.is_empty() {
+            packets.push(format!("O{}", hex_encode(output.as_bytes())).into_bytes());
+        }
+        packets.push(if ok { b"OK".to_vec() } else { b"E01".to_vec() });
+        Reply::Packets(packets)
+    }
+}
+
+enum Reply {
+    None,
+    Packet(Vec<u8>),
+    Packets(Vec<Vec<u8>>),
+    /// 回复（如果有）后关闭连接
+    Close(Option<Vec<u8>>),
+}
+
+impl Reply {
+    fn text(s: &str) -> Self {
+        Reply::Packet(s.as_bytes().to_vec())
+    }
+
+    fn ok() -> Self {
+        Reply::text("OK")
+    }
+
+    fn error() -> Self {
+        Reply::text("E01")
+    }
+
+    fn status(ok: bool) -> Self {
+        if ok { Reply::ok() } else { Reply::error() }
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.trim(), 16).ok()
+}
+
+fn parse_pair(s: &str, sep: char) -> Option<(u32, u32)> {
+    let (a, b) = s.split_once(sep)?;
+    Some((parse_hex(a)?, parse_hex(b)?))
+}
+
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut it = data.iter();
+    while let Some(&b) = it.next() {
+        if b == b'}' {
+            if let Some(&n) = it.next() {
+                out.push(n ^ 0x20);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+fn send_packet(stream: &mut TcpStream, payload: &[u8]) -> io::Result<()> {
+    let csum = payload.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+    let mut pkt = vec![b'$'];
+    pkt.extend_from_slice(payload);
+    pkt.extend_from_slice(format!("#{:02x}", csum).as_bytes());
+    stream.write_all(&pkt)
+}
+
+fn read_byte(stream: &mut TcpStream) -> io::Result<u8> {
+    let mut b = [0u8];
+    stream.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn serve(mut stream: TcpStream, target: &Mutex<SimTarget>) -> io::Result<()> {
+    loop {
+        match read_byte(&mut stream)? {
+            b'$' => {}
+            // Ctrl-C
+            0x03 => {
+                let mut t = target.lock().unwrap();
+                if t.running {
+                    t.running = false;
+                    drop(t);
+                    send_packet(&mut stream, b"S02")?;
+                }
+                continue;
+            }
+            // ACK 和其他杂散字节
+            _ => continue,
+        }
+
+        let mut packet = Vec::new();
+        loop {
+            match read_byte(&mut stream)? {
+                b'#' => break,
+                b => packet.push(b),
+            }
+        }
+        let mut csum = [0u8; 2];
+        stream.read_exact(&mut csum)?;
+        let expected = packet.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+        if parse_hex(&String::from_utf8_lossy(&csum)) != Some(expected as u32) {
+            stream.write_all(b"-")?;
+            continue;
+        }
+
+        let mut t = target.lock().unwrap();
+        t.log.push(String::from_utf8_lossy(&packet).into_owned());
+        let fault = t.take_fault(&packet);
+        let reply = match &fault {
+            Some(Fault::Nack) => {
+                drop(t);
+                stream.write_all(b"-")?;
+                continue;
+            }
+            Some(Fault::Drop) => return Ok(()),
+            Some(Fault::Reply(r)) => Reply::text(r),
+            _ => t.handle(&packet),
+        };
+        drop(t);
+
+        stream.write_all(b"+")?;
+        if let Some(Fault::Delay(d)) = fault {
+            thread::sleep(d);
+        }
+
+        match reply {
+            Reply::None => {}
+            Reply::Packet(p) => send_packet(&mut stream, &p)?,
+            Reply::Packets(ps) => {
+                for p in ps {
+                    send_packet(&mut stream, &p)?;
+                }
+            }
+            Reply::Close(p) => {
+                if let Some(p) = p {
+                    send_packet(&mut stream, &p)?;
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// 在后台线程运行的服务器，drop 时停止
+pub struct FakeServer {
+    port: u16,
+    target: Arc<Mutex<SimTarget>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakeServer {
+    pub fn start() -> Self {
+        Self::with_target(SimTarget::new())
+    }
+
+    pub fn with_target(target: SimTarget) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let target = Arc::new(Mutex::new(target));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let target = target.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                // 一次服务一个连接，和 OpenOCD 的单个 gdb 端口一样
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        let _ = serve(stream, &target);
+                    }
+                }
+            })
+        };
+
+        Self {
+            port,
+            target,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn transport(&self) -> TcpTransport {
+        TcpTransport::new("127.0.0.1", self.port)
+    }
+
+    pub fn target(&self) -> MutexGuard<'_, SimTarget> {
+        self.target.lock().unwrap()
+    }
+}
+
+impl Drop for FakeServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // 唤醒阻塞在 accept 上的线程
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gdb_client::{DisconnectMode, GdbClient};
+    use crate::memory_map::MemoryKind;
+
+    fn connect(server: &FakeServer) -> GdbClient<TcpTransport> {
+        let mut client = GdbClient::new(server.transport());
+        client.connect().unwrap();
+        client
+    }
+
+    #[test]
+    fn test_memory_map_over_tcp() {
+        let server = FakeServer::start();
+        let mut client = connect(&server);
+
+        let map = client.get_memory_map().unwrap();
+        let flash = map.region_at(FLASH_BASE).unwrap();
+        assert_eq!(flash.kind, MemoryKind::Flash);
+        assert_eq!(flash.blocksize, Some(SECTOR_SIZE as u64));
+        assert_eq!(
+            map.region_at(RAM_BASE).map(|r| r.kind),
+            Some(MemoryKind::Ram)
+        );
+    }
+
+    #[test]
+    fn test_flash_program_and_read_back() {
+        let server = FakeServer::start();
+        let mut client = connect(&server);
+
+        let image: Vec<u8> = (0..600u32).map(|i| i as u8).collect();
+        client.flash_erase(FLASH_BASE, SECTOR_SIZE).unwrap();
+        client.flash_write(FLASH_BASE, &image, 256).unwrap();
+        client.flash_done().unwrap();
+
+        assert_eq!(&server.target().flash[..600], &image[..]);
+        assert_eq!(client.read_u32(FLASH_BASE + 4).unwrap(), 0x0706_0504);
+
+        // 没有擦除就写入
+        client.flash_write(FLASH_BASE, &[0u8; 4], 256).unwrap();
+        assert!(client.flash_done().is_err());
+        // 没有对齐到扇区
+        assert!(client.flash_erase(FLASH_BASE + 4, SECTOR_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_ram_registers_and_monitor() {
+        let server = FakeServer::start();
+        server.target().monitor.insert(
+            "adapter speed 4000".into(),
+            ("adapter speed: 4000 kHz\n".into(), true),
+        );
+        let mut client = connect(&server);
+
+        client.write_binary(RAM_BASE, b"}#$*data", 256).unwrap();
+        assert_eq!(&server.target().ram[..8], b"}#$*data");
+
+        client.write_register(13, 0x2000_1000).unwrap();
+        assert_eq!(client.read_register(13).unwrap(), 0x2000_1000);
+
+        assert_eq!(
+            client.monitor("adapter speed 4000").unwrap(),
+            "adapter speed: 4000 kHz\n"
+        );
+        assert!(client.monitor("bogus").is_err());
+
+        client.disconnect_with(DisconnectMode::Detach).unwrap();
+    }
+
+    #[test]
+    fn test_fault_injection() {
+        let server = FakeServer::start();
+        {
+            let mut t = server.target();
+            t.inject("vFlashErase", Fault::Nack, 1);
+            t.inject("vFlashDone", Fault::Reply("E08"), 1);
+            t.inject("m", Fault::Delay(Duration::from_millis(20)), 1);
+            t.inject("qRcmd", Fault::Drop, 1);
+        }
+        let mut client = connect(&server);
+
+        assert!(client.flash_erase(FLASH_BASE, SECTOR_SIZE).is_err());
+        client.flash_erase(FLASH_BASE, SECTOR_SIZE).unwrap();
+
+        assert!(client.flash_done().is_err());
+        client.flash_done().unwrap();
+
+        assert_eq!(client.read_u32(FLASH_BASE).unwrap(), 0xFFFF_FFFF);
+
+        let err = client.monitor("reset halt").unwrap_err();
+        assert!(err.is_disconnect());
+
+        // 服务器继续接受新的连接
+        client.disconnect();
+        let mut client = connect(&server);
+        client.monitor("reset halt").unwrap();
+    }
+}
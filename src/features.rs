@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use crate::agdi_consts::{
+    AG_F_HWBREAK, AG_F_MEMACC, AG_F_MEMACCR, AG_F_MEMMAP, AG_F_REGACC, AG_F_REGACCR, AG_F_RESETR,
+    AG_F_WATCHP,
+};
+
+/// qSupported 的回复，例如 `PacketSize=4000;qXfer:memory-map:read+;QStartNoAckMode+`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerFeatures {
+    features: HashMap<String, String>,
+}
+
+impl ServerFeatures {
+    pub fn parse(reply: &[u8]) -> Self {
+        let mut features = HashMap::new();
+
+        for item in String::from_utf8_lossy(reply).split(';') {
+            let item = item.trim();
+            if item.is_empty() {
+                continue;
+            }
+
+            if let Some((name, value)) = item.split_once('=') {
+                features.insert(name.to_string(), value.to_string());
+            } else if let Some(name) = item.strip_suffix('+') {
+                features.insert(name.to_string(), "+".to_string());
+            } else if let Some(name) = item.strip_suffix('-') {
+                features.insert(name.to_string(), "-".to_string());
+            } else if let Some(name) = item.strip_suffix('?') {
+                features.insert(name.to_string(), "?".to_string());
+            }
+        }
+
+        Self { features }
+    }
+
+    pub fn supports(&self, name: &str) -> bool {
+        self.features.get(name).is_some_and(|v| v == "+")
+    }
+}
+
+/// 连接时从目标读到的调试资源
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TargetCaps {
+    pub hw_breakpoints: usize,
+    pub watchpoints: usize,
+    pub mem_access: bool,
+    pub reg_access: bool,
+}
+
+/// 回答 AG_GETFEATURE 的功能表，连接前全部为 0
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeatureTable {
+    pub hw_breakpoints: u32,
+    pub watchpoints: u32,
+    pub mem_access: bool,
+    pub reg_access: bool,
+    /// 目标运行时读写内存 / 寄存器
+    pub mem_access_running: bool,
+    pub reg_access_running: bool,
+    pub memory_map: bool,
+    pub reset_running: bool,
+}
+
+impl FeatureTable {
+    /// 断点和观察点数量由 FPB / DWT 决定，qSupported 不会报告，由调用者从目标读取
+    pub fn from_server(server: &ServerFeatures, caps: TargetCaps) -> Self {
+        Self {
+            hw_breakpoints: caps.hw_breakpoints as u32,
+            watchpoints: caps.watchpoints as u32,
+            mem_access: caps.mem_access,
+            reg_access: caps.reg_access,
+            // 运行时的访问需要先停止目标，不提供
+            mem_access_running: false,
+            reg_access_running: false,
+            memory_map: server.supports("qXfer:memory-map:read"),
+            reset_running: false,
+        }
+    }
+
+    pub fn query(&self, code: u16) -> u32 {
+        match code {
+            AG_F_MEMACCR => self.mem_access_running as u32,
+            AG_F_REGACCR => self.reg_access_running as u32,
+            AG_F_MEMMAP => self.memory_map as u32,
+            AG_F_RESETR => self.reset_running as u32,
+            AG_F_HWBREAK => self.hw_breakpoints,
+            AG_F_WATCHP => self.watchpoints,
+            AG_F_MEMACC => self.mem_access as u32,
+            AG_F_REGACC => self.reg_access as u32,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPENOCD_REPLY: &[u8] = b"PacketSize=4000;qXfer:memory-map:read+;qXfer:features:read-;qXfer:threads:read+;QStartNoAckMode+;vContSupported+";
+
+    #[test]
+    fn test_parse_qsupported() {
+        let server = ServerFeatures::parse(OPENOCD_REPLY);
+
+        assert_eq!(
+            server.features.get("PacketSize").map(String::as_str),
+            Some("4000")
+        );
+        assert!(server.supports("qXfer:memory-map:read"));
+        assert!(server.supports("QStartNoAckMode"));
+        assert!(!server.supports("qXfer:features:read"));
+        assert!(!server.supports("swbreak"));
+    }
+
+    fn caps(hw_breakpoints: usize, watchpoints: usize) -> TargetCaps {
+        TargetCaps {
+            hw_breakpoints,
+            watchpoints,
+            mem_access: true,
+            reg_access: true,
+        }
+    }
+
+    #[test]
+    fn test_feature_table() {
+        let table = FeatureTable::from_server(&ServerFeatures::parse(OPENOCD_REPLY), caps(6, 4));
+
+        assert_eq!(table.query(AG_F_HWBREAK), 6);
+        assert_eq!(table.query(AG_F_WATCHP), 4);
+        assert_eq!(table.query(AG_F_MEMMAP), 1);
+        assert_eq!(table.query(AG_F_MEMACC), 1);
+        assert_eq!(table.query(AG_F_MEMACCR), 0);
+        assert_eq!(table.query(0xFF), 0);
+
+        let table = FeatureTable::from_server(
+            &ServerFeatures::parse(b"PacketSize=400"),
+            TargetCaps {
+                reg_access: false,
+                ..caps(4, 2)
+            },
+        );
+        assert_eq!(table.query(AG_F_MEMMAP), 0);
+        assert_eq!(table.query(AG_F_REGACC), 0);
+
+        assert_eq!(FeatureTable::default().query(AG_F_HWBREAK), 0);
+    }
+}
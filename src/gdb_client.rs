@@ -1,18 +1,26 @@
-
 use std::io;
 use std::io::Read;
 use std::io::Write;
-use std::io::{Error, ErrorKind};
 use std::net::TcpStream;
+use std::time::Duration;
+
+use log::{debug, trace};
 
-use quick_xml::Reader;
-use quick_xml::events::Event;
+use crate::error::{Error, OpKind, Result};
+use crate::features::ServerFeatures;
+use crate::memory_map::MemoryMap;
+use crate::stop_reply::{StopReply, hex_decode, parse_stop_reply};
 
 pub trait GdbTransport {
     fn connect(&mut self) -> io::Result<()>;
     fn close(&mut self) -> io::Result<()>;
     fn send(&mut self, data: &[u8]) -> io::Result<()>;
     fn recv_exact(&mut self, buf: &mut [u8]) -> io::Result<()>;
+
+    /// None 表示阻塞读取
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 pub struct TcpTransport {
@@ -57,8 +65,29 @@ impl GdbTransport for TcpTransport {
     fn recv_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
         self.stream()?.read_exact(buf)
     }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream()?.set_read_timeout(timeout)
+    }
 }
 
+/// 断开连接的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectMode {
+    /// `D`：detach，目标保持当前状态
+    Detach,
+    /// `k`：kill，由服务器决定复位或停止目标
+    Kill,
+    /// 不通知服务器，直接关闭连接
+    Drop,
+}
+
+/// 断开时等待服务器回复的时间
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 校验和错误时最多接收几次重发的包
+const CHECKSUM_ATTEMPTS: usize = 3;
+
 pub struct GdbClient<T: GdbTransport> {
     transport: T,
     connected: bool,
@@ -76,17 +105,94 @@ impl<T: GdbTransport> GdbClient<T> {
         data.iter().fold(0u8, |s, b| s.wrapping_add(*b))
     }
 
-    pub fn connect(&mut self) -> io::Result<()> {
+    pub fn connect(&mut self) -> Result<()> {
         if self.connected {
             return Ok(());
         }
 
-        self.transport.connect()?;
+        self.transport
+            .connect()
+            .map_err(|e| Error::from(e).during("connect"))?;
         self.connected = true;
+        debug!("connected");
+        Ok(())
+    }
+
+    /// `D`：通知服务器 GDB 将要断开
+    pub fn detach(&mut self) -> Result<()> {
+        let resp = self.send_cmd("D", &[])?;
+        if resp != b"OK" {
+            return Err(Error::from_reply("detach", OpKind::Other, None, &resp));
+        }
         Ok(())
     }
 
-    pub fn disconnect(&mut self)  {
+    /// `qRcmd`：执行 OpenOCD 的 monitor 命令，返回命令输出的文本
+    pub fn monitor(&mut self, cmd: &str) -> Result<String> {
+        let hex: String = cmd.bytes().map(|b| format!("{:02x}", b)).collect();
+        let mut resp = self.send_cmd(&format!("qRcmd,{}", hex), &[])?;
+
+        // 输出以 `O<hex>` 包分段发送，直到最终的 OK / Exx
+        let mut output = Vec::new();
+        while resp.first() == Some(&b'O') && resp != b"OK" {
+            output.extend(hex_decode(&resp[1..])?);
+            resp = self.read_packet().map_err(|e| e.during("qRcmd"))?;
+        }
+        let output = String::from_utf8_lossy(&output).into_owned();
+
+        match resp.as_slice() {
+            b"OK" => {
+                debug!("monitor {}: {}", cmd, output.trim_end());
+                Ok(output)
+            }
+            b"" => Err(Error::Unsupported { op: "qRcmd".into() }),
+            _ => Err(Error::Monitor {
+                cmd: cmd.to_string(),
+                reply: String::from_utf8_lossy(&resp).into_owned(),
+                output,
+            }),
+        }
+    }
+
+    /// `k`：服务器可能不回复直接关闭连接，这种情况不算错误
+    pub fn kill(&mut self) -> Result<()> {
+        let result = self.send_packet("k", &[]).and_then(|_| self.read_packet());
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_disconnect() || e.is_timeout() => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn with_timeout<R>(
+        &mut self,
+        timeout: Duration,
+        f: impl FnOnce(&mut Self) -> Result<R>,
+    ) -> Result<R> {
+        self.transport.set_read_timeout(Some(timeout))?;
+        let result = f(self);
+        let _ = self.transport.set_read_timeout(None);
+        result
+    }
+
+    /// 按 mode 通知服务器后关闭连接，通知失败时连接也会被关闭
+    pub fn disconnect_with(&mut self, mode: DisconnectMode) -> Result<()> {
+        if !self.connected {
+            return Ok(());
+        }
+
+        debug!("disconnect ({:?})", mode);
+        let result = match mode {
+            DisconnectMode::Detach => self.with_timeout(DISCONNECT_TIMEOUT, |c| c.detach()),
+            DisconnectMode::Kill => self.with_timeout(DISCONNECT_TIMEOUT, |c| c.kill()),
+            DisconnectMode::Drop => Ok(()),
+        };
+
+        self.disconnect();
+        result
+    }
+
+    pub fn disconnect(&mut self) {
         if !self.connected {
             return;
         }
@@ -109,7 +215,7 @@ impl<T: GdbTransport> GdbClient<T> {
         out
     }
 
-    fn recv_byte(&mut self) -> io::Result<u8> {
+    fn recv_byte(&mut self) -> Result<u8> {
         let mut b = [0u8];
         self.transport.recv_exact(&mut b)?;
         Ok(b[0])
@@ -117,7 +223,7 @@ impl<T: GdbTransport> GdbClient<T> {
 }
 
 impl<T: GdbTransport> GdbClient<T> {
-    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+    fn read_packet(&mut self) -> Result<Vec<u8>> {
         // 等待 '$'
         loop {
             if self.recv_byte()? == b'$' {
@@ -125,6 +231,41 @@ impl<T: GdbTransport> GdbClient<T> {
             }
         }
 
+        self.read_packet_checked()
+    }
+
+    /// 在 timeout 内等待一个包的起始 '$'，超时返回 None
+    fn poll_packet(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        self.transport.set_read_timeout(Some(timeout))?;
+        let first = self.recv_byte();
+        self.transport.set_read_timeout(None)?;
+
+        match first {
+            Ok(b'$') => self.read_packet_checked().map(Some),
+            // 杂散的 ACK 等字节
+            Ok(_) => Ok(None),
+            Err(e) if e.is_timeout() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 校验和错误时回复 '-' 让服务器重发；次数用完后回复 '+' 让服务器停止重发，
+    /// 否则重发的包会被当作下一个命令的回复
+    fn read_packet_checked(&mut self) -> Result<Vec<u8>> {
+        let mut attempt = 1;
+        loop {
+            let retry = attempt < CHECKSUM_ATTEMPTS;
+            match self.read_packet_body(retry) {
+                Err(Error::Checksum { .. }) if retry => {
+                    attempt += 1;
+                    while self.recv_byte()? != b'$' {}
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn read_packet_body(&mut self, retry: bool) -> Result<Vec<u8>> {
         let mut payload = Vec::new();
 
         loop {
@@ -135,9 +276,32 @@ impl<T: GdbTransport> GdbClient<T> {
             payload.push(b);
         }
 
-        // 丢弃 checksum
         let mut checksum = [0u8; 2];
         self.transport.recv_exact(&mut checksum)?;
+        trace!("<- {}", payload.escape_ascii());
+
+        let actual = Self::checksum(&payload);
+        match std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok())
+        {
+            Some(expected) if expected == actual => {}
+            Some(expected) => {
+                self.transport.send(if retry { b"-" } else { b"+" })?;
+                return Err(Error::Checksum {
+                    op: String::new(),
+                    expected,
+                    actual,
+                });
+            }
+            None => {
+                return Err(Error::Malformed {
+                    op: "packet".into(),
+                    kind: OpKind::Other,
+                    reply: format!("bad checksum {}", checksum.escape_ascii()),
+                });
+            }
+        }
 
         // ACK
         self.transport.send(b"+")?;
@@ -147,7 +311,18 @@ impl<T: GdbTransport> GdbClient<T> {
 }
 
 impl<T: GdbTransport> GdbClient<T> {
-    pub fn send_cmd(&mut self, prefix: &str, binary: &[u8]) -> io::Result<Vec<u8>> {
+    pub fn send_cmd(&mut self, prefix: &str, binary: &[u8]) -> Result<Vec<u8>> {
+        self.send_packet(prefix, binary)?;
+        self.read_packet().map_err(|e| e.during(prefix))
+    }
+
+    /// 发送一个包并等待 ACK，不读取回复
+    fn send_packet(&mut self, prefix: &str, binary: &[u8]) -> Result<()> {
+        self.send_packet_inner(prefix, binary)
+            .map_err(|e| e.during(prefix))
+    }
+
+    fn send_packet_inner(&mut self, prefix: &str, binary: &[u8]) -> Result<()> {
         let mut body = Vec::new();
         body.extend_from_slice(prefix.as_bytes());
         body.extend_from_slice(binary);
@@ -160,33 +335,32 @@ impl<T: GdbTransport> GdbClient<T> {
         pkt.push(b'#');
         pkt.extend_from_slice(format!("{:02x}", csum).as_bytes());
 
+        trace!("-> {}", body.escape_ascii());
         self.transport.send(&pkt)?;
 
         // 等 ACK
         match self.recv_byte()? {
             b'+' => {}
-            b'-' => return Err(io::Error::new(io::ErrorKind::Other, "NACK")),
+            b'-' => return Err(Error::Nack { op: String::new() }),
             b => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("unexpected ACK: {}", b),
-                ));
+                return Err(Error::Malformed {
+                    op: "ACK".into(),
+                    kind: OpKind::Other,
+                    reply: format!("{:#04x}", b),
+                });
             }
         }
 
-        self.read_packet()
+        Ok(())
     }
 }
 
 impl<T: GdbTransport> GdbClient<T> {
-    pub fn flash_erase(&mut self, addr: u32, len: u32) -> io::Result<()> {
+    pub fn flash_erase(&mut self, addr: u32, len: u32) -> Result<()> {
         let resp = self.send_cmd(&format!("vFlashErase:{:x},{:x}", addr, len), &[])?;
 
         if resp != b"OK" {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("erase failed: {:?}", resp),
-            ));
+            return Err(Error::from_reply("erase", OpKind::Write, Some(addr), &resp));
         }
         Ok(())
     }
@@ -195,15 +369,15 @@ impl<T: GdbTransport> GdbClient<T> {
 const FLASH_WORD: usize = 4;
 
 impl<T: GdbTransport> GdbClient<T> {
-    pub fn flash_write(&mut self, addr: u32, data: &[u8], chunk: usize) -> io::Result<()> {
+    pub fn flash_write(&mut self, addr: u32, data: &[u8], chunk: usize) -> Result<()> {
         let mut offset = 0usize;
 
         while offset < data.len() {
             let mut block = data[offset..usize::min(offset + chunk, data.len())].to_vec();
 
-            if block.len() % FLASH_WORD != 0 {
+            if !block.len().is_multiple_of(FLASH_WORD) {
                 let pad = FLASH_WORD - (block.len() % FLASH_WORD);
-                block.extend(std::iter::repeat(0xFF).take(pad));
+                block.extend(std::iter::repeat_n(0xFF, pad));
             }
 
             let escaped = Self::escape_binary(&block);
@@ -214,9 +388,11 @@ impl<T: GdbTransport> GdbClient<T> {
             )?;
 
             if resp != b"OK" {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("write failed @0x{:x}", addr + offset as u32),
+                return Err(Error::from_reply(
+                    "write",
+                    OpKind::Write,
+                    Some(addr + offset as u32),
+                    &resp,
                 ));
             }
 
@@ -228,20 +404,201 @@ impl<T: GdbTransport> GdbClient<T> {
 }
 
 impl<T: GdbTransport> GdbClient<T> {
-    pub fn flash_done(&mut self) -> io::Result<()> {
+    pub fn flash_done(&mut self) -> Result<()> {
         let resp = self.send_cmd("vFlashDone", &[])?;
         if resp != b"OK" {
-            return Err(io::Error::new(io::ErrorKind::Other, "FlashDone failed"));
+            return Err(Error::from_reply("flash done", OpKind::Write, None, &resp));
         }
         Ok(())
     }
 }
 #[allow(dead_code)]
 impl<T: GdbTransport> GdbClient<T> {
-    pub fn read_memory(&mut self, addr: u32, len: u32) -> io::Result<String> {
+    pub fn read_memory(&mut self, addr: u32, len: u32) -> Result<String> {
         let resp = self.send_cmd(&format!("m{:x},{:x}", addr, len), &[])?;
         Ok(String::from_utf8_lossy(&resp).into_owned())
     }
+
+    /// 读取一个小端 32 位字
+    pub fn read_u32(&mut self, addr: u32) -> Result<u32> {
+        let resp = self.send_cmd(&format!("m{:x},4", addr), &[])?;
+        if resp.len() != 8 || resp[0] == b'E' {
+            return Err(Error::from_reply(
+                "read memory",
+                OpKind::Read,
+                Some(addr),
+                &resp,
+            ));
+        }
+        let bytes = hex_decode(&resp)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// `X`：以二进制分段写入内存，用于下载到 RAM
+    pub fn write_binary(&mut self, addr: u32, data: &[u8], chunk: usize) -> Result<()> {
+        for (i, block) in data.chunks(chunk).enumerate() {
+            let at = addr + (i * chunk) as u32;
+            let escaped = Self::escape_binary(block);
+            let resp = self.send_cmd(&format!("X{:x},{:x}:", at, block.len()), &escaped)?;
+            if resp != b"OK" {
+                return Err(Error::from_reply(
+                    "write memory",
+                    OpKind::Write,
+                    Some(at),
+                    &resp,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// `P`：写单个 32 位寄存器
+    pub fn write_register(&mut self, regnum: u32, value: u32) -> Result<()> {
+        let hex: String = value
+            .to_le_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let resp = self.send_cmd(&format!("P{:x}={}", regnum, hex), &[])?;
+        if resp != b"OK" {
+            return Err(Error::from_reply(
+                &format!("write register {}", regnum),
+                OpKind::Write,
+                None,
+                &resp,
+            ));
+        }
+        Ok(())
+    }
+
+    /// `M`：以十六进制写入内存
+    pub fn write_memory(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+        let resp = self.send_cmd(&format!("M{:x},{:x}:{}", addr, data.len(), hex), &[])?;
+        if resp != b"OK" {
+            return Err(Error::from_reply(
+                "write memory",
+                OpKind::Write,
+                Some(addr),
+                &resp,
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl<T: GdbTransport> GdbClient<T> {
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+}
+
+// 运行控制
+impl<T: GdbTransport> GdbClient<T> {
+    /// `?`：查询目标当前停止原因
+    pub fn query_halt_reason(&mut self) -> Result<StopReply> {
+        self.send_packet("?", &[])?;
+        self.wait_stop_reply()
+    }
+
+    /// `c`：继续运行，停止回复稍后由 wait/poll 读取
+    pub fn resume(&mut self) -> Result<()> {
+        self.send_packet("c", &[])
+    }
+
+    /// `s`：单步并等待停止回复
+    pub fn step(&mut self) -> Result<StopReply> {
+        self.send_packet("s", &[])?;
+        self.wait_stop_reply()
+    }
+
+    /// 发送 Ctrl-C 中断正在运行的目标，它不是一个包，也没有 ACK
+    pub fn interrupt(&mut self) -> Result<()> {
+        trace!("-> <Ctrl-C>");
+        self.transport
+            .send(&[0x03])
+            .map_err(|e| Error::from(e).during("interrupt"))
+    }
+
+    /// 阻塞读取停止回复，跳过途中的 `O` 控制台输出
+    pub fn wait_stop_reply(&mut self) -> Result<StopReply> {
+        loop {
+            let pkt = self.read_packet()?;
+            match parse_stop_reply(&pkt)? {
+                StopReply::Output(_) => continue,
+                reply => return Ok(reply),
+            }
+        }
+    }
+
+    /// 在 timeout 内没有停止回复时返回 None
+    pub fn poll_stop_reply(&mut self, timeout: Duration) -> Result<Option<StopReply>> {
+        match self.poll_packet(timeout)? {
+            Some(pkt) => match parse_stop_reply(&pkt)? {
+                StopReply::Output(_) => Ok(None),
+                reply => Ok(Some(reply)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// `p`：读取单个 32 位寄存器（目标字节序为小端）
+    pub fn read_register(&mut self, regnum: u32) -> Result<u32> {
+        let resp = self.send_cmd(&format!("p{:x}", regnum), &[])?;
+        let op = format!("read register {}", regnum);
+        if resp.len() < 8 || resp[0] == b'E' {
+            return Err(Error::from_reply(&op, OpKind::Read, None, &resp));
+        }
+
+        let bytes = hex_decode(&resp[..8]).map_err(|_| Error::Malformed {
+            op,
+            kind: OpKind::Read,
+            reply: String::from_utf8_lossy(&resp).into_owned(),
+        })?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+impl<T: GdbTransport> GdbClient<T> {
+    /// 告诉服务器我们能处理 swbreak / hwbreak 停止原因，并取得服务器支持的功能
+    pub fn query_supported(&mut self) -> Result<ServerFeatures> {
+        let resp = self.send_cmd("qSupported:swbreak+;hwbreak+", &[])?;
+        if resp.first() == Some(&b'E') {
+            return Err(Error::from_reply("qSupported", OpKind::Other, None, &resp));
+        }
+        Ok(ServerFeatures::parse(&resp))
+    }
+}
+
+// 断点 / 观察点
+impl<T: GdbTransport> GdbClient<T> {
+    /// `Z<type>,addr,kind`：type 0 = 软件断点，1 = 硬件断点
+    pub fn insert_breakpoint(&mut self, z_type: u8, addr: u32, kind: u32) -> Result<()> {
+        let resp = self.send_cmd(&format!("Z{},{:x},{:x}", z_type, addr, kind), &[])?;
+        Self::check_z_reply(&resp, "insert", z_type, addr)
+    }
+
+    pub fn remove_breakpoint(&mut self, z_type: u8, addr: u32, kind: u32) -> Result<()> {
+        let resp = self.send_cmd(&format!("z{},{:x},{:x}", z_type, addr, kind), &[])?;
+        Self::check_z_reply(&resp, "remove", z_type, addr)
+    }
+
+    fn check_z_reply(resp: &[u8], op: &str, z_type: u8, addr: u32) -> Result<()> {
+        match resp {
+            b"OK" => Ok(()),
+            // 空回复表示服务器不支持该类型
+            b"" => Err(Error::Unsupported {
+                op: format!("Z{}", z_type),
+            }),
+            _ => Err(Error::from_reply(
+                &format!("{} Z{}", op, z_type),
+                OpKind::Other,
+                Some(addr),
+                resp,
+            )),
+        }
+    }
 }
 
 /// 一个简单的 MockTransport，用于测试 GdbClient
@@ -265,7 +622,7 @@ impl MockTransport {
             sent_packets: Vec::new(),
             recv_buffer,
             recv_pos: 0,
-            connected: connected,
+            connected,
         }
     }
 
@@ -293,7 +650,10 @@ impl GdbTransport for MockTransport {
 
     fn send(&mut self, data: &[u8]) -> io::Result<()> {
         if !self.connected {
-            return Err(Error::new(ErrorKind::NotConnected, "mock not connected"));
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "mock not connected",
+            ));
         }
         self.sent_packets.push(data.to_vec());
         Ok(())
@@ -301,11 +661,17 @@ impl GdbTransport for MockTransport {
 
     fn recv_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
         if !self.connected {
-            return Err(Error::new(ErrorKind::NotConnected, "mock not connected"));
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "mock not connected",
+            ));
         }
 
         if self.recv_pos + buf.len() > self.recv_buffer.len() {
-            return Err(Error::new(ErrorKind::UnexpectedEof, "mock: no more data"));
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "mock: no more data",
+            ));
         }
 
         buf.copy_from_slice(&self.recv_buffer[self.recv_pos..self.recv_pos + buf.len()]);
@@ -314,106 +680,31 @@ impl GdbTransport for MockTransport {
     }
 }
 
-fn parse_hex_u64(s: &str) -> Result<u64, std::num::ParseIntError> {
-    let s = s.trim();
-
-    let s = s
-        .strip_prefix("0x")
-        .or_else(|| s.strip_prefix("0X"))
-        .unwrap_or(s);
-
-    u64::from_str_radix(s, 16)
-}
-
-fn parse_flash_regions_from_xml(xml: &[u8]) -> io::Result<Vec<FlashRegion>> {
-    let mut reader = Reader::from_reader(xml);
-    reader.trim_text(true);
-
-    let mut regions = Vec::new();
-    let mut buf = Vec::new();
-
-    let mut cur: Option<FlashRegion> = None;
-
-    loop {
-        match reader.read_event_into(&mut buf) {
-            Ok(Event::Start(e)) if e.name().as_ref() == b"memory" => {
-                let mut start = 0;
-                let mut length = 0;
-                let mut is_flash = false;
-
-                for a in e.attributes().flatten() {
-                    match a.key.as_ref() {
-                        b"type" => {
-                            is_flash = a.value.as_ref() == b"flash";
-                        }
-                        b"start" => {
-                            start =
-                                parse_hex_u64(str::from_utf8(a.value.as_ref()).unwrap()).unwrap();
-                        }
-                        b"length" => {
-                            length =
-                                parse_hex_u64(str::from_utf8(a.value.as_ref()).unwrap()).unwrap();
-                        }
-                        _ => {}
-                    }
-                }
-
-                if is_flash {
-                    cur = Some(FlashRegion {
-                        start,
-                        length,
-                        blocksize: None,
-                    });
-                }
-            }
+impl<T: GdbTransport> GdbClient<T> {
+    /// 分段读取 memory-map XML，直到服务器回复 'l'
+    pub fn get_memory_map(&mut self) -> Result<MemoryMap> {
+        let mut xml = Vec::new();
 
-            Ok(Event::Text(e)) => {
-                if let Some(r) = cur.as_mut() {
-                    let txt = e.unescape().unwrap();
-                    if let Ok(bs) = parse_hex_u64(&txt) {
-                        r.blocksize = Some(bs);
-                    }
-                }
+        loop {
+            let resp =
+                self.send_cmd(&format!("qXfer:memory-map:read::{:x},fff", xml.len()), &[])?;
+
+            if resp.is_empty() || resp[0] == b'E' {
+                return Err(Error::from_reply(
+                    "memory-map read",
+                    OpKind::Read,
+                    None,
+                    &resp,
+                ));
             }
 
-            Ok(Event::End(e)) if e.name().as_ref() == b"memory" => {
-                if let Some(r) = cur.take() {
-                    regions.push(r);
-                }
+            xml.extend_from_slice(&resp[1..]); // 去掉 m / l
+            if resp[0] == b'l' {
+                break;
             }
-
-            Ok(Event::Eof) => break,
-
-            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
-
-            _ => {}
         }
 
-        buf.clear();
-    }
-
-    Ok(regions)
-}
-
-#[derive(Debug)]
-#[allow(dead_code)]
-pub struct FlashRegion {
-    pub start: u64,
-    pub length: u64,
-    pub blocksize: Option<u64>,
-}
-
-impl<T: GdbTransport> GdbClient<T> {
-    pub fn get_flash_info(&mut self) -> io::Result<Vec<FlashRegion>> {
-        let resp = self.send_cmd("qXfer:memory-map:read::0,fff", &[])?;
-
-        if resp.is_empty() || resp[0] == b'E' {
-            return Err(io::Error::new(io::ErrorKind::Other, "qXfer failed"));
-        }
-
-        let xml: &[u8] = &resp[1..]; // 去掉 m / l
-
-        parse_flash_regions_from_xml(xml)
+        MemoryMap::parse(&xml)
     }
 }
 
@@ -520,6 +811,53 @@ mod tests {
         assert!(sent_str.contains("m20000000,8"));
     }
 
+    #[test]
+    fn test_read_u32_write_memory() {
+        let responses = vec![
+            vec![b'+'],
+            MockTransport::rsp_packet(b"edaaff0f"),
+            vec![b'+'],
+            MockTransport::rsp_packet(b"OK"),
+            vec![b'+'],
+            MockTransport::rsp_packet(b"E14"),
+        ];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+
+        assert_eq!(client.read_u32(0x1fff_c000).unwrap(), 0x0fff_aaed);
+        client.write_memory(0x4002_3c14, &[0x01, 0x02]).unwrap();
+        assert!(client.read_u32(0x1fff_c000).is_err());
+
+        let sent = String::from_utf8_lossy(&client.transport.sent_packets[2]).into_owned();
+        assert!(sent.starts_with("$M40023c14,2:0102#"));
+    }
+
+    #[test]
+    fn test_write_binary_and_register() {
+        let responses = vec![
+            vec![b'+'],
+            MockTransport::rsp_packet(b"OK"),
+            vec![b'+'],
+            MockTransport::rsp_packet(b"OK"),
+            vec![b'+'],
+            MockTransport::rsp_packet(b"OK"),
+        ];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+
+        client
+            .write_binary(0x2000_0000, &[0x01, b'#', 0x03], 2)
+            .unwrap();
+        client.write_register(15, 0x2000_0101).unwrap();
+
+        let sent = &client.transport.sent_packets;
+        assert!(sent[0].starts_with(b"$X20000000,2:\x01}\x03#"));
+        assert!(sent[2].starts_with(b"$X20000002,1:\x03#"));
+        assert!(sent[4].starts_with(b"$Pf=01010020#"));
+    }
+
     #[test]
     fn test_nack_error() {
         let responses = vec![
@@ -533,6 +871,48 @@ mod tests {
         assert!(err.to_string().contains("NACK"));
     }
 
+    #[test]
+    fn test_bad_checksum() {
+        // 服务器收到 '-' 后重发
+        let responses = vec![
+            vec![b'+'],
+            b"$OK#00".to_vec(),
+            MockTransport::rsp_packet(b"OK"),
+            vec![b'+'],
+            MockTransport::rsp_packet(b"12345678"),
+        ];
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+
+        assert_eq!(client.send_cmd("vFlashDone", &[]).unwrap(), b"OK");
+        assert_eq!(client.transport.sent_packets[1], b"-");
+        // 下一个命令得到的是自己的回复
+        assert_eq!(client.send_cmd("m0,4", &[]).unwrap(), b"12345678");
+    }
+
+    #[test]
+    fn test_bad_checksum_retries_exhausted() {
+        let mut responses = vec![vec![b'+']];
+        responses.extend(std::iter::repeat_n(b"$OK#00".to_vec(), CHECKSUM_ATTEMPTS));
+        responses.push(vec![b'+']);
+        responses.push(MockTransport::rsp_packet(b"12345678"));
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+
+        let err = client.send_cmd("vFlashDone", &[]).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Checksum {
+                ref op,
+                expected: 0,
+                actual: 0x9a
+            } if op == "vFlashDone"
+        ));
+        // 最后一次回复 '+'，服务器不会再重发
+        assert_eq!(client.transport.sent_packets.last().unwrap(), b"+");
+        assert_eq!(client.send_cmd("m0,4", &[]).unwrap(), b"12345678");
+    }
+
     #[test]
     fn test_client_connect_disconnect() {
         let transport = MockTransport::new(Vec::new(), false);
@@ -547,24 +927,201 @@ mod tests {
         assert!(!client.connected);
     }
     #[test]
-    fn test_parse_flash_regions_from_xml() {
-        let xml = br#"
-<memory-map>
-  <memory type="ram" start="0x00000000" length="0x08000000"/>
-  <memory type="flash" start="0x08000000" length="0x8000">
-    <property name="blocksize">0x400</property>
-  </memory>
-  <memory type="ram" start="0x08008000" length="0xf7ff8000"/>
-</memory-map>
-"#;
-
-        let regions = parse_flash_regions_from_xml(xml).unwrap();
-
-        assert_eq!(regions.len(), 1);
-
-        let r = &regions[0];
-        assert_eq!(r.start, 0x0800_0000);
-        assert_eq!(r.length, 0x8000);
-        assert_eq!(r.blocksize, Some(0x400));
+    fn test_query_halt_reason() {
+        let responses = vec![
+            vec![b'+'],
+            MockTransport::rsp_packet(b"T05thread:1;0f:00010008;"),
+        ];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+
+        let StopReply::Stopped(ev) = client.query_halt_reason().unwrap() else {
+            panic!("expected stop reply");
+        };
+        assert_eq!(ev.pc(), Some(0x0800_0100));
+
+        let sent = String::from_utf8_lossy(&client.transport.sent_packets[0]).into_owned();
+        assert!(sent.starts_with("$?#"));
+    }
+
+    #[test]
+    fn test_step_skips_console_output() {
+        let responses = vec![
+            vec![b'+'],
+            MockTransport::rsp_packet(b"O6869"),
+            MockTransport::rsp_packet(b"S05"),
+        ];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+
+        let reply = client.step().unwrap();
+        assert!(matches!(reply, StopReply::Stopped(ev) if ev.signal == 5));
+    }
+
+    #[test]
+    fn test_query_supported() {
+        let responses = vec![
+            vec![b'+'],
+            MockTransport::rsp_packet(b"PacketSize=4000;qXfer:memory-map:read+"),
+        ];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+
+        let features = client.query_supported().unwrap();
+        assert!(features.supports("qXfer:memory-map:read"));
+
+        let sent = String::from_utf8_lossy(&client.transport.sent_packets[0]).into_owned();
+        assert!(sent.starts_with("$qSupported:swbreak+;hwbreak+#"));
+    }
+
+    #[test]
+    fn test_insert_remove_breakpoint() {
+        let responses = vec![
+            vec![b'+'],
+            MockTransport::rsp_packet(b"OK"),
+            vec![b'+'],
+            MockTransport::rsp_packet(b"E0e"),
+        ];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+
+        client.insert_breakpoint(1, 0x0800_0100, 2).unwrap();
+        let err = client.remove_breakpoint(0, 0x2000_0000, 2).unwrap_err();
+        assert!(err.to_string().contains("E0e"));
+
+        let sent = String::from_utf8_lossy(&client.transport.sent_packets[0]).into_owned();
+        assert!(sent.starts_with("$Z1,8000100,2#"));
+        let sent = String::from_utf8_lossy(&client.transport.sent_packets[2]).into_owned();
+        assert!(sent.starts_with("$z0,20000000,2#"));
+    }
+
+    #[test]
+    fn test_get_memory_map_in_chunks() {
+        let responses = vec![
+            vec![b'+'],
+            MockTransport::rsp_packet(br#"m<memory-map><memory type="ram" start="0x20000000" "#),
+            vec![b'+'],
+            MockTransport::rsp_packet(br#"llength="0x5000"/></memory-map>"#),
+        ];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+
+        let map = client.get_memory_map().unwrap();
+        assert_eq!(map.regions.len(), 1);
+        assert_eq!(map.regions[0].length, 0x5000);
+
+        let sent = String::from_utf8_lossy(&client.transport.sent_packets[2]).into_owned();
+        assert!(sent.contains("qXfer:memory-map:read::32,fff"));
+    }
+
+    #[test]
+    fn test_monitor() {
+        let responses = vec![
+            vec![b'+'],
+            MockTransport::rsp_packet(b"O726573657474696e670a"),
+            MockTransport::rsp_packet(b"OK"),
+        ];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+        client.connect().unwrap();
+
+        let output = client.monitor("reset run").unwrap();
+        assert_eq!(output, "resetting\n");
+
+        let sent = String::from_utf8_lossy(&client.transport.sent_packets[0]).into_owned();
+        assert!(sent.starts_with("$qRcmd,72657365742072756e#"));
+    }
+
+    #[test]
+    fn test_monitor_multiple_output_packets() {
+        let responses = vec![
+            vec![b'+'],
+            MockTransport::rsp_packet(b"O6164617074657220"),
+            MockTransport::rsp_packet(b"O73706565643a2034303030206b487a0a"),
+            MockTransport::rsp_packet(b"OK"),
+        ];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+        client.connect().unwrap();
+
+        let output = client.monitor("adapter speed 4000").unwrap();
+        assert_eq!(output, "adapter speed: 4000 kHz\n");
+    }
+
+    #[test]
+    fn test_monitor_error() {
+        let responses = vec![
+            vec![b'+'],
+            MockTransport::rsp_packet(b"O696e76616c696420636f6d6d616e640a"),
+            MockTransport::rsp_packet(b"E01"),
+        ];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+        client.connect().unwrap();
+
+        let err = client.monitor("bogus").unwrap_err();
+        assert!(err.to_string().contains("invalid command"));
+    }
+
+    #[test]
+    fn test_disconnect_detach() {
+        let responses = vec![vec![b'+'], MockTransport::rsp_packet(b"OK")];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+        client.connect().unwrap();
+
+        client.disconnect_with(DisconnectMode::Detach).unwrap();
+        assert!(!client.connected);
+
+        let sent = String::from_utf8_lossy(&client.transport.sent_packets[0]).into_owned();
+        assert!(sent.starts_with("$D#"));
+    }
+
+    #[test]
+    fn test_disconnect_detach_error_still_closes() {
+        let responses = vec![vec![b'+'], MockTransport::rsp_packet(b"E01")];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+        client.connect().unwrap();
+
+        assert!(client.disconnect_with(DisconnectMode::Detach).is_err());
+        assert!(!client.connected);
+    }
+
+    #[test]
+    fn test_disconnect_kill_without_reply() {
+        // 服务器收到 k 后直接关闭连接
+        let responses = vec![vec![b'+']];
+
+        let transport = MockTransport::new(responses, true);
+        let mut client = GdbClient::new(transport);
+        client.connect().unwrap();
+
+        client.disconnect_with(DisconnectMode::Kill).unwrap();
+        assert!(!client.connected);
+
+        let sent = String::from_utf8_lossy(&client.transport.sent_packets[0]).into_owned();
+        assert!(sent.starts_with("$k#"));
+    }
+
+    #[test]
+    fn test_disconnect_drop() {
+        let transport = MockTransport::new(Vec::new(), true);
+        let mut client = GdbClient::new(transport);
+        client.connect().unwrap();
+
+        client.disconnect_with(DisconnectMode::Drop).unwrap();
+        assert!(!client.connected);
+        assert!(client.transport.sent_packets.is_empty());
     }
 }
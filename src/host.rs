@@ -0,0 +1,259 @@
+use core::ffi::c_void;
+use std::ffi::CString;
+use std::os::raw::c_char;
+
+#[cfg(windows)]
+use user32::MessageBoxA;
+#[cfg(windows)]
+use winapi::winuser::{MB_ICONINFORMATION, MB_OK};
+
+use crate::agdi_consts::{
+    AG_CB_EXECCMD, AG_CB_GETFLASHPARAM, AG_CB_MSGSTRING, AG_CB_PROGRESS, AG_CB_TRUEXPR,
+    PROGRESS_INIT, PROGRESS_KILL, PROGRESS_SETPOS,
+};
+use crate::agdi_impl::{FlashParm, PgRess};
+
+/// 去掉 NUL，保证消息不会因此变成空串
+fn c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap_or_default()
+}
+
+/// uVision 的回调，返回 U32；Keil 是 32 位进程，返回的指针也放在 U32 里
+pub type Pcbf = extern "C" fn(n_code: u32, vp: *mut c_void) -> u32;
+
+/// 把回调返回的 U32 还原成指针
+#[cfg(not(test))]
+fn ret_ptr<P>(ret: u32) -> *mut P {
+    ret as usize as *mut P
+}
+
+/// 64 位下的测试放不下指针，HostSim 返回的是指针表的下标
+#[cfg(test)]
+fn ret_ptr<P>(ret: u32) -> *mut P {
+    crate::host_sim::pointer(ret) as *mut P
+}
+
+/// uVision 通过回调提供给驱动的功能
+pub trait Host {
+    /// AG_INITCALLBACK 传入的回调
+    fn set_callback(&mut self, vp: *mut c_void);
+    fn has_callback(&self) -> bool;
+
+    fn progress_init(&mut self, label: &str);
+    fn progress_set(&mut self, pos: i32);
+    fn progress_kill(&mut self);
+
+    /// prev 为 null 时返回第一段，返回 null 或 many == 0 表示结束
+    fn flash_param(&mut self, prev: *mut FlashParm) -> *mut FlashParm;
+
+    /// 断点条件，ep 为 AG_Bps.ep
+    fn eval_condition(&mut self, ep: *mut c_void) -> bool;
+
+    /// 断点命令，cmd 为 AG_Bps.cmd
+    fn exec_command(&mut self, cmd: *mut c_void);
+
+    /// 输出到 uVision 的 Command 窗口，不打断用户
+    fn message(&mut self, text: &str);
+}
+
+/// 向用户显示严重错误，例如无法连接服务器；需要用户确认
+pub trait Notifier {
+    fn notify(&mut self, message: &str, title: &str);
+}
+
+/// 通过 Keil 的 Pcbf 回调实现 Host
+#[derive(Default)]
+pub struct KeilHost {
+    callback: Option<Pcbf>,
+}
+
+impl KeilHost {
+    fn call(&self, n_code: u32, vp: *mut c_void) -> u32 {
+        match self.callback {
+            Some(cb) => cb(n_code, vp),
+            None => 0,
+        }
+    }
+
+    fn progress(&self, job: i32, pos: i32, label: *mut c_char) {
+        let mut pg_ress = PgRess {
+            job,
+            pos,
+            low: 0,
+            hig: 100,
+            label,
+            ctext: std::ptr::null_mut(),
+        };
+        self.call(AG_CB_PROGRESS, &mut pg_ress as *mut _ as *mut c_void);
+    }
+}
+
+impl Host for KeilHost {
+    fn set_callback(&mut self, vp: *mut c_void) {
+        if !vp.is_null() {
+            let cb: Pcbf = unsafe { std::mem::transmute(vp) };
+            self.callback = Some(cb);
+        }
+    }
+
+    fn has_callback(&self) -> bool {
+        self.callback.is_some()
+    }
+
+    fn progress_init(&mut self, label: &str) {
+        let c_label = c_string(label);
+        self.progress(PROGRESS_INIT, 0, c_label.into_raw());
+    }
+
+    fn progress_set(&mut self, pos: i32) {
+        self.progress(PROGRESS_SETPOS, pos, std::ptr::null_mut());
+    }
+
+    fn progress_kill(&mut self) {
+        self.progress(PROGRESS_KILL, 0, std::ptr::null_mut());
+    }
+
+    fn flash_param(&mut self, prev: *mut FlashParm) -> *mut FlashParm {
+        ret_ptr(self.call(AG_CB_GETFLASHPARAM, prev as *mut c_void))
+    }
+
+    fn eval_condition(&mut self, ep: *mut c_void) -> bool {
+        self.call(AG_CB_TRUEXPR, ep) != 0
+    }
+
+    fn exec_command(&mut self, cmd: *mut c_void) {
+        self.call(AG_CB_EXECCMD, cmd);
+    }
+
+    fn message(&mut self, text: &str) {
+        // 输出窗口不会自动换行
+        let c_text = c_string(&format!("{}\n", text));
+        self.call(AG_CB_MSGSTRING, c_text.as_ptr() as *mut c_void);
+    }
+}
+
+/// uVision 中显示的消息框
+#[cfg(windows)]
+#[derive(Default)]
+pub struct MessageBoxNotifier;
+
+#[cfg(windows)]
+impl Notifier for MessageBoxNotifier {
+    fn notify(&mut self, message: &str, title: &str) {
+        let lp_text = c_string(message);
+        let lp_caption = c_string(title);
+        unsafe {
+            MessageBoxA(
+                std::ptr::null_mut(),
+                lp_text.as_ptr(),
+                lp_caption.as_ptr(),
+                MB_OK | MB_ICONINFORMATION,
+            );
+        }
+    }
+}
+
+/// 没有图形界面时输出到 stderr
+#[cfg(not(windows))]
+#[derive(Default)]
+pub struct HeadlessNotifier;
+
+#[cfg(not(windows))]
+impl Notifier for HeadlessNotifier {
+    fn notify(&mut self, message: &str, title: &str) {
+        eprintln!("{}: {}", title, message);
+    }
+}
+
+#[cfg(windows)]
+pub type DefaultNotifier = MessageBoxNotifier;
+#[cfg(not(windows))]
+pub type DefaultNotifier = HeadlessNotifier;
+
+/// 测试用：没有 uVision 时的 Host
+#[cfg(test)]
+#[derive(Default)]
+pub struct NullHost;
+
+#[cfg(test)]
+impl Host for NullHost {
+    fn set_callback(&mut self, _vp: *mut c_void) {}
+    fn has_callback(&self) -> bool {
+        false
+    }
+    fn progress_init(&mut self, _label: &str) {}
+    fn progress_set(&mut self, _pos: i32) {}
+    fn progress_kill(&mut self) {}
+
+    fn flash_param(&mut self, _prev: *mut FlashParm) -> *mut FlashParm {
+        std::ptr::null_mut()
+    }
+
+    fn eval_condition(&mut self, _ep: *mut c_void) -> bool {
+        true
+    }
+
+    fn exec_command(&mut self, _cmd: *mut c_void) {}
+    fn message(&mut self, _text: &str) {}
+}
+
+/// 测试用：按顺序给出断点条件的结果（用完后总是成立），记录执行的断点命令
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct ScriptedHost {
+    pub conditions: std::collections::VecDeque<bool>,
+    pub commands: Vec<usize>,
+    /// 输出窗口中的消息
+    pub messages: Vec<String>,
+}
+
+#[cfg(test)]
+impl Host for ScriptedHost {
+    fn set_callback(&mut self, _vp: *mut c_void) {}
+    fn has_callback(&self) -> bool {
+        false
+    }
+    fn progress_init(&mut self, _label: &str) {}
+    fn progress_set(&mut self, _pos: i32) {}
+    fn progress_kill(&mut self) {}
+
+    fn flash_param(&mut self, _prev: *mut FlashParm) -> *mut FlashParm {
+        std::ptr::null_mut()
+    }
+
+    fn eval_condition(&mut self, _ep: *mut c_void) -> bool {
+        self.conditions.pop_front().unwrap_or(true)
+    }
+
+    fn exec_command(&mut self, cmd: *mut c_void) {
+        self.commands.push(cmd as usize);
+    }
+
+    fn message(&mut self, text: &str) {
+        self.messages.push(text.to_string());
+    }
+}
+
+/// 测试用：记录所有通知，不弹出消息框
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct RecordingNotifier {
+    pub messages: Vec<(String, String)>,
+}
+
+#[cfg(test)]
+impl Notifier for RecordingNotifier {
+    fn notify(&mut self, message: &str, title: &str) {
+        self.messages.push((message.to_string(), title.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_c_string_strips_nul() {
+        assert_eq!(c_string("E04\0 at 0x0").as_bytes(), b"E04 at 0x0");
+    }
+}
@@ -0,0 +1,778 @@
+//! 模拟 uVision：实现 Pcbf 回调，提供 FlashParm 链并记录进度条事件和输出窗口的消息，
+//! 按 uVision 的顺序调用 AG_Init
+
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+use core::ffi::c_void;
+
+use crate::agdi_consts::{
+    AG_CB_GETFLASHPARAM, AG_CB_MSGSTRING, AG_CB_PROGRESS, AG_CB_TRUEXPR, AG_INITCALLBACK,
+    AG_INITFLASHLOAD, AG_INITITEM, AG_NOACCESS, AG_OK, AG_STARTFLASHLOAD, AG_WRFAILED,
+    PROGRESS_INIT, PROGRESS_KILL, PROGRESS_SETPOS,
+};
+use crate::agdi_impl::{Agdi, FlashParm, PgRess};
+use crate::gdb_client::{GdbTransport, TcpTransport};
+use crate::host::{KeilHost, Notifier, Pcbf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    Init(String),
+    SetPos(i32),
+    Kill,
+}
+
+#[derive(Default)]
+struct HostState {
+    /// 最后一项 many == 0，表示链结束
+    params: Vec<FlashParm>,
+    _images: Vec<Vec<u8>>,
+    progress: Vec<ProgressEvent>,
+    messages: Vec<String>,
+}
+
+// Pcbf 没有上下文参数，状态放在线程局部变量里，并行的测试互不影响
+thread_local! {
+    static HOST: RefCell<Option<HostState>> = const { RefCell::new(None) };
+}
+
+fn with_host<R>(f: impl FnOnce(&mut HostState) -> R) -> R {
+    HOST.with(|h| f(h.borrow_mut().as_mut().expect("HostSim not installed")))
+}
+
+/// 指针表：回调返回 params 的下标加一，0 表示 null
+pub(crate) fn pointer(handle: u32) -> *mut c_void {
+    if handle == 0 {
+        return std::ptr::null_mut();
+    }
+    with_host(|h| {
+        h.params
+            .get_mut(handle as usize - 1)
+            .map_or(std::ptr::null_mut(), |p| p as *mut FlashParm as *mut c_void)
+    })
+}
+
+extern "C" fn host_callback(n_code: u32, vp: *mut c_void) -> u32 {
+    match n_code {
+        AG_CB_GETFLASHPARAM => with_host(|h| {
+            let next = if vp.is_null() {
+                0
+            } else {
+                match h
+                    .params
+                    .iter()
+                    .position(|p| std::ptr::eq(p, vp as *const FlashParm))
+                {
+                    Some(i) => i + 1,
+                    None => return 0,
+                }
+            };
+            if next < h.params.len() {
+                next as u32 + 1
+            } else {
+                0
+            }
+        }),
+        AG_CB_PROGRESS => {
+            let pg = unsafe { &*(vp as *const PgRess) };
+            let (job, pos, label) = (pg.job, pg.pos, pg.label);
+            let event = match job {
+                PROGRESS_INIT => ProgressEvent::Init(if label.is_null() {
+                    String::new()
+                } else {
+                    unsafe { CStr::from_ptr(label) }
+                        .to_string_lossy()
+                        .into_owned()
+                }),
+                PROGRESS_SETPOS => ProgressEvent::SetPos(pos),
+                PROGRESS_KILL => ProgressEvent::Kill,
+                _ => return 0,
+            };
+            with_host(|h| h.progress.push(event));
+            0
+        }
+        AG_CB_MSGSTRING => {
+            let text = unsafe { CStr::from_ptr(vp as *const c_char) }
+                .to_string_lossy()
+                .into_owned();
+            with_host(|h| h.messages.push(text));
+            0
+        }
+        // 断点条件总是成立
+        AG_CB_TRUEXPR => 1,
+        _ => 0,
+    }
+}
+
+/// 安装在当前线程上的模拟 host，drop 时卸载
+pub struct HostSim;
+
+impl HostSim {
+    /// chunks 为 Keil 下载时依次给出的 (地址, 数据)
+    pub fn new(chunks: &[(u32, &[u8])]) -> Self {
+        let mut images: Vec<Vec<u8>> = chunks.iter().map(|(_, d)| d.to_vec()).collect();
+        let act_size = images.iter().map(|d| d.len() as u32).sum();
+
+        let mut params: Vec<FlashParm> = chunks
+            .iter()
+            .zip(images.iter_mut())
+            .map(|((addr, _), data)| FlashParm {
+                start: *addr,
+                many: data.len() as u32,
+                image: data.as_mut_ptr(),
+                act_size,
+                stop_and_flags: 0,
+                res: [0; 16],
+            })
+            .collect();
+        params.push(FlashParm {
+            start: 0,
+            many: 0,
+            image: std::ptr::null_mut(),
+            act_size,
+            stop_and_flags: 0,
+            res: [0; 16],
+        });
+
+        HOST.with(|h| {
+            *h.borrow_mut() = Some(HostState {
+                params,
+                _images: images,
+                ..Default::default()
+            })
+        });
+        HostSim
+    }
+
+    pub fn callback() -> *mut c_void {
+        host_callback as Pcbf as *mut c_void
+    }
+
+    pub fn progress(&self) -> Vec<ProgressEvent> {
+        with_host(|h| h.progress.clone())
+    }
+
+    /// 输出到 Command 窗口的消息，保留末尾的换行
+    pub fn messages(&self) -> Vec<String> {
+        with_host(|h| h.messages.clone())
+    }
+
+    /// uVision 的下载顺序：注册回调、准备下载、开始下载
+    pub fn download<T: GdbTransport, N: Notifier>(&self, agdi: &mut Agdi<T, KeilHost, N>) -> u32 {
+        let status = agdi.init(AG_INITITEM | AG_INITCALLBACK, Self::callback());
+        if status != AG_OK {
+            return status;
+        }
+        let status = agdi.init(AG_INITITEM | AG_INITFLASHLOAD, std::ptr::null_mut());
+        if status != AG_OK {
+            return status;
+        }
+        agdi.init(AG_INITITEM | AG_STARTFLASHLOAD, std::ptr::null_mut())
+    }
+}
+
+impl Drop for HostSim {
+    fn drop(&mut self) {
+        HOST.with(|h| h.borrow_mut().take());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agdi_consts::{AG_EXECITEM, AG_INVALOP, AG_RESET};
+    use crate::config::{Config, ExitAction, PostDownload};
+    use crate::fake_server::{
+        FLASH_BASE, FLASH_SIZE, FakeServer, OTP_BASE, RAM_BASE, RAM_SIZE, SECTOR_SIZE,
+    };
+    use crate::host::RecordingNotifier;
+    use crate::option_bytes::{OptionByte, OptionBytes};
+    use crate::protection::{Protection, ProtectionCheck};
+
+    fn agdi_for(server: &FakeServer) -> Agdi<TcpTransport, KeilHost, RecordingNotifier> {
+        agdi_with(server, Config::default())
+    }
+
+    fn agdi_with(
+        server: &FakeServer,
+        config: Config,
+    ) -> Agdi<TcpTransport, KeilHost, RecordingNotifier> {
+        Agdi::with_parts(
+            config,
+            server.transport(),
+            KeilHost::default(),
+            RecordingNotifier::default(),
+        )
+    }
+
+    /// 服务器收到的以 prefix 开头的包
+    fn packets(server: &FakeServer, prefix: &str) -> Vec<String> {
+        server
+            .target()
+            .log
+            .iter()
+            .filter(|p| p.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    fn monitor_packet(cmd: &str) -> String {
+        let hex: String = cmd.bytes().map(|b| format!("{:02x}", b)).collect();
+        format!("qRcmd,{}", hex)
+    }
+
+    #[test]
+    fn test_download_to_flash() {
+        let server = FakeServer::start();
+        let image: Vec<u8> = (0..0x600u32).map(|i| (i * 7) as u8).collect();
+        let host = HostSim::new(&[
+            (FLASH_BASE, &image[..0x300]),
+            (FLASH_BASE + 0x300, &image[0x300..]),
+        ]);
+
+        let mut agdi = agdi_for(&server);
+        assert_eq!(host.download(&mut agdi), AG_OK);
+
+        let target = server.target();
+        assert_eq!(&target.flash[..0x600], &image[..]);
+        // 两个扇区各擦除一次
+        let erases: Vec<_> = target
+            .log
+            .iter()
+            .filter(|p| p.starts_with("vFlashErase"))
+            .cloned()
+            .collect();
+        assert_eq!(
+            erases,
+            vec![
+                format!("vFlashErase:{:x},{:x}", FLASH_BASE, SECTOR_SIZE),
+                format!(
+                    "vFlashErase:{:x},{:x}",
+                    FLASH_BASE + SECTOR_SIZE,
+                    SECTOR_SIZE
+                ),
+            ]
+        );
+
+        assert_eq!(
+            host.progress(),
+            vec![
+                ProgressEvent::Init("Loading...".into()),
+                ProgressEvent::SetPos(50),
+                ProgressEvent::SetPos(100),
+                ProgressEvent::Kill,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_download_to_ram() {
+        let server = FakeServer::start();
+        let host = HostSim::new(&[(RAM_BASE, b"ram image")]);
+
+        let mut agdi = agdi_for(&server);
+        assert_eq!(host.download(&mut agdi), AG_OK);
+        assert_eq!(&server.target().ram[..9], b"ram image");
+        assert!(
+            !server
+                .target()
+                .log
+                .iter()
+                .any(|p| p.starts_with("vFlashWrite"))
+        );
+    }
+
+    #[test]
+    fn test_download_fails_on_write_error() {
+        let server = FakeServer::start();
+        server
+            .target()
+            .inject("vFlashDone", crate::fake_server::Fault::Reply("E01"), 1);
+        let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+
+        let mut agdi = agdi_for(&server);
+        assert_eq!(host.download(&mut agdi), AG_WRFAILED);
+        let messages = host.messages();
+        assert!(messages[0].contains("may be read-out protected"));
+        assert_eq!(
+            messages[1],
+            "Download failed: flash done failed: server replied E01\n"
+        );
+        // 下载失败不弹出消息框
+        assert!(agdi.notifier().messages.is_empty());
+        assert_eq!(host.progress().last(), Some(&ProgressEvent::Kill));
+    }
+
+    #[test]
+    fn test_erase_by_regions() {
+        let server = FakeServer::start();
+        server.target().flash[..4].copy_from_slice(&[1, 2, 3, 4]);
+        // Keil 的 Erase 命令：FlashParm 链只有结束项
+        let host = HostSim::new(&[]);
+
+        let mut agdi = agdi_for(&server);
+        assert_eq!(host.download(&mut agdi), AG_OK);
+
+        let step = SECTOR_SIZE * 16;
+        assert_eq!(
+            packets(&server, "vFlashErase"),
+            vec![
+                format!("vFlashErase:{:x},{:x}", FLASH_BASE, step),
+                format!("vFlashErase:{:x},{:x}", FLASH_BASE + step, step),
+            ]
+        );
+        assert_eq!(packets(&server, "vFlashDone").len(), 1);
+        assert!(server.target().flash.iter().all(|&b| b == 0xFF));
+        assert_eq!(
+            host.progress(),
+            vec![
+                ProgressEvent::Init("Erasing...".into()),
+                ProgressEvent::SetPos(50),
+                ProgressEvent::SetPos(100),
+                ProgressEvent::Kill,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_erase_with_monitor_command() {
+        let server = FakeServer::start();
+        server.target().monitor.insert(
+            "stm32f1x mass_erase 0".into(),
+            ("stm32x mass erase complete\n".into(), true),
+        );
+        let host = HostSim::new(&[]);
+        let config = Config {
+            mass_erase_cmd: Some("stm32f1x mass_erase 0".into()),
+            ..Config::default()
+        };
+
+        let mut agdi = agdi_with(&server, config);
+        assert_eq!(host.download(&mut agdi), AG_OK);
+        assert_eq!(
+            packets(&server, "qRcmd"),
+            vec![monitor_packet("stm32f1x mass_erase 0")]
+        );
+        assert!(packets(&server, "vFlashErase").is_empty());
+    }
+
+    /// 跨越两个块大小不同的 flash 区域的段，分别按各自的块大小擦除和写入
+    #[test]
+    fn test_download_across_regions() {
+        let server = FakeServer::start();
+        let split = FLASH_BASE + 0x1000;
+        server.target().memory_map = format!(
+            r#"<memory-map>
+<memory type="flash" start="{:#x}" length="0x1000"><property name="blocksize">{:#x}</property></memory>
+<memory type="flash" start="{:#x}" length="{:#x}"><property name="blocksize">0x1000</property></memory>
+<memory type="ram" start="{:#x}" length="{:#x}"/>
+</memory-map>"#,
+            FLASH_BASE,
+            SECTOR_SIZE,
+            split,
+            FLASH_SIZE - 0x1000,
+            RAM_BASE,
+            RAM_SIZE
+        );
+        let image: Vec<u8> = (0..0x800u32).map(|i| (i * 3) as u8).collect();
+        let host = HostSim::new(&[(split - 0x400, &image)]);
+
+        let mut agdi = agdi_for(&server);
+        assert_eq!(host.download(&mut agdi), AG_OK);
+        assert_eq!(
+            packets(&server, "vFlashErase"),
+            vec!["vFlashErase:8000c00,400", "vFlashErase:8001000,1000"]
+        );
+        let target = server.target();
+        let off = (split - 0x400 - FLASH_BASE) as usize;
+        assert_eq!(&target.flash[off..off + image.len()], &image[..]);
+    }
+
+    #[test]
+    fn test_no_erase_without_callback() {
+        let server = FakeServer::start();
+        let _host = HostSim::new(&[]);
+
+        // 没有 AG_INITCALLBACK 时不能把空的参数当作擦除命令
+        let mut agdi = agdi_for(&server);
+        assert_eq!(
+            agdi.init(AG_INITITEM | AG_INITFLASHLOAD, std::ptr::null_mut()),
+            AG_OK
+        );
+        assert_eq!(
+            agdi.init(AG_INITITEM | AG_STARTFLASHLOAD, std::ptr::null_mut()),
+            AG_NOACCESS
+        );
+        assert!(packets(&server, "vFlashErase").is_empty());
+    }
+
+    #[test]
+    fn test_download_out_of_order() {
+        let server = FakeServer::start();
+        let image: Vec<u8> = (0..0x800u32).map(|i| (i * 3) as u8).collect();
+        // 高地址的段先到
+        let host = HostSim::new(&[
+            (FLASH_BASE + 0x400, &image[0x400..]),
+            (FLASH_BASE, &image[..0x400]),
+        ]);
+
+        let mut agdi = agdi_for(&server);
+        assert_eq!(host.download(&mut agdi), AG_OK);
+        assert_eq!(&server.target().flash[..0x800], &image[..]);
+        assert_eq!(
+            packets(&server, "vFlashErase"),
+            vec![
+                format!(
+                    "vFlashErase:{:x},{:x}",
+                    FLASH_BASE + SECTOR_SIZE,
+                    SECTOR_SIZE
+                ),
+                format!("vFlashErase:{:x},{:x}", FLASH_BASE, SECTOR_SIZE),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ram_run() {
+        let server = FakeServer::start();
+        let mut image = Vec::new();
+        image.extend_from_slice(&(RAM_BASE + 0x1000).to_le_bytes());
+        image.extend_from_slice(&(RAM_BASE + 0x41).to_le_bytes());
+        image.extend_from_slice(&[0; 0x40]);
+        let host = HostSim::new(&[(RAM_BASE, &image)]);
+        let config = Config {
+            ram_run: true,
+            flash_exit_action: ExitAction::Kill,
+            ..Config::default()
+        };
+
+        let mut agdi = agdi_with(&server, config);
+        assert_eq!(host.download(&mut agdi), AG_OK);
+        assert_eq!(
+            agdi.init(AG_EXECITEM | AG_RESET, std::ptr::null_mut()),
+            AG_OK
+        );
+
+        let target = server.target();
+        assert_eq!(target.regs[13], RAM_BASE + 0x1000);
+        // 去掉 Thumb 位
+        assert_eq!(target.regs[15], RAM_BASE + 0x40);
+        let vtor: Vec<u8> = (0..4).map(|i| target.periph[&(0xE000_ED08 + i)]).collect();
+        assert_eq!(vtor, RAM_BASE.to_le_bytes());
+        assert!(target.running);
+        // RAM 镜像不能再复位，也不能在断开时被 kill
+        assert!(!target.log.iter().any(|p| p.starts_with("qRcmd")));
+        assert!(!target.log.iter().any(|p| p == "k"));
+    }
+
+    fn flash_cmds(pre: &[&str], post: &[&str]) -> Config {
+        Config {
+            pre_flash_cmds: pre.iter().map(|c| c.to_string()).collect(),
+            post_flash_cmds: post.iter().map(|c| c.to_string()).collect(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_pre_flash_cmd_failure() {
+        let server = FakeServer::start();
+        let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+
+        let mut agdi = agdi_with(&server, flash_cmds(&["reset halt", "bogus"], &[]));
+        assert_eq!(host.download(&mut agdi), AG_NOACCESS);
+        // 在擦除前中止
+        assert!(packets(&server, "vFlash").is_empty());
+        let messages = host.messages();
+        assert!(messages[0].starts_with("Pre-download command failed:"));
+        assert!(messages[0].contains("invalid command name \"bogus\""));
+        assert!(agdi.notifier().messages.is_empty());
+    }
+
+    #[test]
+    fn test_post_flash_cmd_failure() {
+        let server = FakeServer::start();
+        server.target().monitor.insert(
+            "my_verify_proc".into(),
+            ("verify failed at 0x08000010\n".into(), false),
+        );
+        let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+
+        let mut agdi = agdi_with(&server, flash_cmds(&[], &["my_verify_proc"]));
+        assert_eq!(host.download(&mut agdi), AG_NOACCESS);
+        assert_eq!(packets(&server, "vFlashDone").len(), 1);
+        let messages = host.messages();
+        assert!(messages[0].starts_with("Post-download command failed:"));
+        assert!(messages[0].contains("verify failed at 0x08000010"));
+    }
+
+    #[test]
+    fn test_flash_cmds_order() {
+        let server = FakeServer::start();
+        for cmd in ["adapter speed 4000", "verify a", "verify b"] {
+            server
+                .target()
+                .monitor
+                .insert(cmd.into(), (String::new(), true));
+        }
+        let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+
+        let config = flash_cmds(
+            &["reset halt", "adapter speed 4000"],
+            &["verify a", "verify b"],
+        );
+        let mut agdi = agdi_with(&server, config);
+        assert_eq!(host.download(&mut agdi), AG_OK);
+
+        let expected = [
+            monitor_packet("reset halt"),
+            monitor_packet("adapter speed 4000"),
+            "vFlashErase".to_string(),
+            "vFlashDone".to_string(),
+            monitor_packet("verify a"),
+            monitor_packet("verify b"),
+        ];
+        let target = server.target();
+        let order: Vec<&String> = target
+            .log
+            .iter()
+            .filter_map(|p| expected.iter().find(|e| p.starts_with(e.as_str())))
+            .collect();
+        assert_eq!(order, expected.iter().collect::<Vec<_>>());
+    }
+
+    /// vFlashDone 之后的包
+    fn after_flash_done(server: &FakeServer) -> Vec<String> {
+        let target = server.target();
+        let done = target.log.iter().position(|p| p == "vFlashDone").unwrap();
+        target.log[done + 1..].to_vec()
+    }
+
+    #[test]
+    fn test_reset_after_download() {
+        // (模式, vFlashDone 之后的包, 目标是否运行, AG_RESET 之后的全部 monitor 命令)
+        for (post_download, expected, running, resets) in [
+            (
+                PostDownload::None,
+                vec![],
+                false,
+                vec![monitor_packet("reset run")],
+            ),
+            (
+                PostDownload::ResetRun,
+                vec![monitor_packet("reset run")],
+                true,
+                // 已经复位过，AG_RESET 不再复位
+                vec![monitor_packet("reset run")],
+            ),
+            (
+                PostDownload::ResetHalt,
+                vec![monitor_packet("reset halt")],
+                false,
+                vec![monitor_packet("reset halt"), monitor_packet("reset run")],
+            ),
+        ] {
+            let server = FakeServer::start();
+            let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+            let config = Config {
+                post_download,
+                ..Config::default()
+            };
+
+            let mut agdi = agdi_with(&server, config);
+            assert_eq!(host.download(&mut agdi), AG_OK);
+            assert_eq!(after_flash_done(&server), expected, "{:?}", post_download);
+            assert_eq!(server.target().running, running);
+
+            // uVision 的 "Reset and Run"
+            assert_eq!(
+                agdi.init(AG_EXECITEM | AG_RESET, std::ptr::null_mut()),
+                AG_OK
+            );
+            assert_eq!(packets(&server, "qRcmd"), resets, "{:?}", post_download);
+            assert!(server.target().running);
+        }
+    }
+
+    fn option_bytes(ob: OptionBytes) -> Config {
+        Config {
+            option_bytes: ob,
+            ..Config::default()
+        }
+    }
+
+    fn ob(s: &str) -> OptionByte {
+        OptionByte::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_option_bytes_after_download() {
+        let server = FakeServer::start();
+        let apply = "stm32f4x options_write 0 0xEC";
+        server
+            .target()
+            .monitor
+            .insert(apply.into(), (String::new(), true));
+        let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+        let config = option_bytes(OptionBytes {
+            apply: vec![apply.into()],
+            write: vec![ob("0x40023C14 0x0FFFAAEC")],
+            verify: vec![ob("0x40023C14 0xAAEC 0xFFFF")],
+            ..OptionBytes::default()
+        });
+
+        let mut agdi = agdi_with(&server, config);
+        assert_eq!(host.download(&mut agdi), AG_OK);
+        // 下载完成后依次执行 apply、write，再回读校验
+        assert_eq!(
+            after_flash_done(&server),
+            vec![
+                monitor_packet(apply),
+                "M40023c14,4:ecaaff0f".to_string(),
+                "m40023c14,4".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_option_bytes_otp() {
+        let server = FakeServer::start();
+        let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+        let config = option_bytes(OptionBytes {
+            otp: vec![ob("0x1FFF7800 0x12345678")],
+            allow_irreversible: true,
+            ..OptionBytes::default()
+        });
+
+        let mut agdi = agdi_with(&server, config);
+        assert_eq!(host.download(&mut agdi), AG_OK);
+        let after = after_flash_done(&server);
+        assert!(after[0].starts_with("vFlashWrite:1fff7800:"));
+        assert_eq!(after[1..], ["vFlashDone", "m1fff7800,4"]);
+        let otp: Vec<u8> = (0..4)
+            .map(|i| server.target().periph[&(OTP_BASE + i)])
+            .collect();
+        assert_eq!(otp, 0x1234_5678u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_option_bytes_verify_mismatch() {
+        let server = FakeServer::start();
+        server
+            .target()
+            .periph
+            .extend((0..4).map(|i| (0x1FFF_C000 + i, 0x55ecu32.to_le_bytes()[i as usize])));
+        let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+        let config = option_bytes(OptionBytes {
+            verify: vec![ob("0x1FFFC000 0xAAEC 0xFFFF")],
+            ..OptionBytes::default()
+        });
+
+        let mut agdi = agdi_with(&server, config);
+        assert_eq!(host.download(&mut agdi), AG_WRFAILED);
+        assert_eq!(
+            host.messages(),
+            vec![
+                "Option bytes failed:\nverify failed at 0x1fffc000: \
+                 read 0x000055ec, expected 0x0000aaec (mask 0x0000ffff)\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_option_bytes_refused() {
+        let server = FakeServer::start();
+        let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+        let config = option_bytes(OptionBytes {
+            otp: vec![ob("0x1FFF7800 0x12345678")],
+            ..OptionBytes::default()
+        });
+
+        let mut agdi = agdi_with(&server, config);
+        assert_eq!(host.download(&mut agdi), AG_INVALOP);
+        // 在擦除前拒绝，什么都没有写
+        assert!(packets(&server, "vFlash").is_empty());
+        assert!(host.messages()[0].starts_with("Option bytes: refusing irreversible"));
+        assert!(!server.target().periph.contains_key(&OTP_BASE));
+    }
+
+    fn protection(check: bool, allow_unlock: bool) -> Config {
+        Config {
+            protection: Protection {
+                check: check.then(|| ProtectionCheck::Monitor {
+                    cmd: "stm32f1x options_read 0".into(),
+                    pattern: "read protection: on".into(),
+                }),
+                unlock: vec!["stm32f1x unlock 0".into()],
+                allow_unlock,
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_protected_unlock_refused() {
+        let server = FakeServer::start();
+        server.target().protected = true;
+        let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+
+        let mut agdi = agdi_with(&server, protection(true, false));
+        assert_eq!(host.download(&mut agdi), AG_INVALOP);
+        // 擦除前检测到保护，不尝试下载
+        assert!(packets(&server, "vFlash").is_empty());
+        assert!(host.messages()[0].starts_with("Read-out protection: refusing to unlock"));
+        assert!(server.target().protected);
+    }
+
+    #[test]
+    fn test_protected_unlock_and_download() {
+        let server = FakeServer::start();
+        server.target().protected = true;
+        let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+
+        let mut agdi = agdi_with(&server, protection(true, true));
+        assert_eq!(host.download(&mut agdi), AG_OK);
+        let target = server.target();
+        assert!(!target.protected);
+        assert_eq!(target.flash[..4], [1, 2, 3, 4]);
+    }
+
+    /// 没有配置检查方法时，擦除失败后解除保护并重新下载
+    #[test]
+    fn test_protected_retry_after_error() {
+        let server = FakeServer::start();
+        server.target().protected = true;
+        let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+
+        let mut agdi = agdi_with(&server, protection(false, true));
+        assert_eq!(host.download(&mut agdi), AG_OK);
+        let erase = format!("vFlashErase:{:x},{:x}", FLASH_BASE, SECTOR_SIZE);
+        let unlock = monitor_packet("stm32f1x unlock 0");
+        let target = server.target();
+        let order: Vec<&String> = target
+            .log
+            .iter()
+            .filter(|p| **p == erase || **p == unlock)
+            .collect();
+        assert_eq!(order, vec![&erase, &unlock, &erase]);
+        assert_eq!(target.flash[..4], [1, 2, 3, 4]);
+        assert_eq!(
+            host.messages(),
+            vec!["Read-out protection removed, downloading again\n"]
+        );
+    }
+
+    #[test]
+    fn test_protected_error_without_unlock() {
+        let server = FakeServer::start();
+        server.target().protected = true;
+        let host = HostSim::new(&[(FLASH_BASE, &[1, 2, 3, 4])]);
+
+        let mut agdi = agdi_with(&server, protection(false, false));
+        assert_eq!(host.download(&mut agdi), AG_WRFAILED);
+        let messages = host.messages();
+        assert!(messages[0].contains("may be read-out protected"));
+        assert!(messages[1].starts_with("Download failed: erase failed"));
+        assert!(packets(&server, "qRcmd").is_empty());
+    }
+}
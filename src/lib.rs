@@ -1,36 +1,58 @@
 mod agdi_consts;
 mod agdi_impl;
+mod breakpoints;
+mod config;
+mod error;
+#[cfg(test)]
+mod fake_server;
+mod features;
 mod gdb_client;
+mod host;
+#[cfg(test)]
+mod host_sim;
+mod logging;
+mod memory_map;
+mod option_bytes;
+mod protection;
+mod recording;
+#[cfg(test)]
+mod replay_transport;
+#[cfg(test)]
+mod scripted_transport;
+mod stop_reply;
+mod target_state;
 
 use core::ffi::c_void;
 
-use crate::{agdi_consts::AG_NOACCESS, agdi_impl::{AG_Bps, GADR, GVAL}};
+use crate::{agdi_consts::{AG_GETFEATURE, AG_NOACCESS}, agdi_impl::{AG_Bps, GADR, GVAL, with_agdi}};
 
 #[unsafe(no_mangle)]
 pub extern "C" fn AG_Init(n_code: u16, vp: *mut c_void) -> u32 {
-    agdi_impl::get_agdi().lock().unwrap().init(n_code, vp)
+    // 出错时不能报告支持某项功能
+    let fallback = if n_code & 0xFF00 == AG_GETFEATURE { 0 } else { AG_NOACCESS };
+    with_agdi("AG_Init", fallback, |agdi| agdi.init(n_code, vp))
 }
 
 // Keil 会检查这些函数的存在，即使不调用它们
 
 #[unsafe(no_mangle)]
-pub extern "C" fn AG_MemAtt(_n_code: u16, _n_attr: u32, _pa: *mut GADR) -> u32 {
-    AG_NOACCESS
+pub extern "C" fn AG_MemAtt(n_code: u16, n_attr: u32, pa: *mut GADR) -> u32 {
+    with_agdi("AG_MemAtt", AG_NOACCESS, |agdi| agdi.mem_att(n_code, n_attr, pa))
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn AG_BpInfo(_n_code: u16, _vp: *mut c_void) -> u32 {
-    AG_NOACCESS
+pub extern "C" fn AG_BpInfo(n_code: u16, vp: *mut c_void) -> u32 {
+    with_agdi("AG_BpInfo", 0, |agdi| agdi.bp_info(n_code, vp))
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn AG_BreakFunc(_n_code: u16, _n1: u16, _pa: *mut GADR, _pb: *mut AG_Bps) -> u32 {
-    0
+pub extern "C" fn AG_BreakFunc(n_code: u16, n1: u16, pa: *mut GADR, pb: *mut AG_Bps) -> u32 {
+    with_agdi("AG_BreakFunc", AG_NOACCESS, |agdi| agdi.break_func(n_code, n1, pa, pb))
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn AG_GoStep(_n_code: u16, _n_steps: u32, _pa: *mut GADR) -> u32 {
-    0
+pub extern "C" fn AG_GoStep(n_code: u16, n_steps: u32, pa: *mut GADR) -> u32 {
+    agdi_impl::go_step(n_code, n_steps, pa)
 }
 
 #[unsafe(no_mangle)]
@@ -60,10 +82,10 @@ pub extern "C" fn AG_HistFunc(_n_code: u32,_index:i32,_dir:i32, _vp: *mut c_void
 
 #[unsafe(no_mangle)]
 pub extern "C" fn DllUv3Cap(n_code: u32, _vp: *mut c_void) -> i32 {
-    agdi_impl::get_agdi().lock().unwrap().dll_uv3_cap(n_code, _vp)
+    with_agdi("DllUv3Cap", 0, |agdi| agdi.dll_uv3_cap(n_code, _vp))
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn EnumUvARM7(_vp: *mut c_void, n_code: u16) -> u32 {
-    agdi_impl::get_agdi().lock().unwrap().enum_uv_arm7(n_code)
+    with_agdi("EnumUvARM7", 0, |agdi| agdi.enum_uv_arm7(n_code))
 }
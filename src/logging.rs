@@ -0,0 +1,140 @@
+//! 写入文件的 `log` 实现：Keil 加载的 DLL 没有控制台，诊断信息只能写文件
+//!
+//! 每行：`<秒数> <级别> <模块>: <消息>`，秒数从日志初始化开始计算。
+
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::panic;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::config::Config;
+
+pub struct FileLogger {
+    level: LevelFilter,
+    start: Instant,
+    sink: Mutex<Box<dyn Write + Send>>,
+}
+
+impl FileLogger {
+    pub fn new(sink: Box<dyn Write + Send>, level: LevelFilter) -> Self {
+        Self {
+            level,
+            start: Instant::now(),
+            sink: Mutex::new(sink),
+        }
+    }
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let target = record.target();
+        let target = target.strip_prefix("openocd_agdi::").unwrap_or(target);
+        // 其他线程 panic 后仍然继续写日志
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(
+            sink,
+            "{:10.3} {:<5} {}: {}",
+            self.start.elapsed().as_secs_f64(),
+            record.level(),
+            target,
+            record.args()
+        );
+        let _ = sink.flush();
+    }
+
+    fn flush(&self) {
+        let _ = self.sink.lock().unwrap_or_else(|e| e.into_inner()).flush();
+    }
+}
+
+/// 按配置安装全局 logger，未配置日志文件时不记录
+pub fn init(config: &Config) -> io::Result<()> {
+    let Some(path) = &config.log_file else {
+        return Ok(());
+    };
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    writeln!(
+        file,
+        "# openocd_agdi {}, unix time {}",
+        env!("CARGO_PKG_VERSION"),
+        started
+    )?;
+
+    let logger = FileLogger::new(Box::new(file), config.log_level);
+    // 只能安装一次，DLL 重新初始化时沿用已有的 logger
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(config.log_level);
+
+        // DLL 没有 stderr，panic 的位置也写入日志
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            log::error!("{}", info);
+            default_hook(info);
+        }));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay_transport::SharedBuf;
+    use log::Level;
+
+    fn log(logger: &FileLogger, level: Level, target: &str, msg: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .args(format_args!("{}", msg))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_format_and_level() {
+        let buf = SharedBuf::default();
+        let logger = FileLogger::new(Box::new(buf.clone()), LevelFilter::Debug);
+
+        log(
+            &logger,
+            Level::Error,
+            "openocd_agdi::agdi_impl",
+            "erase failed",
+        );
+        log(
+            &logger,
+            Level::Debug,
+            "openocd_agdi::gdb_client",
+            "connected",
+        );
+        log(
+            &logger,
+            Level::Trace,
+            "openocd_agdi::gdb_client",
+            "-> $g#67",
+        );
+
+        let text = buf.text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" ERROR agdi_impl: erase failed"));
+        assert!(lines[1].ends_with(" DEBUG gdb_client: connected"));
+    }
+}
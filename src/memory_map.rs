@@ -0,0 +1,311 @@
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+
+use crate::agdi_consts::{AG_ATR_EXEC, AG_ATR_READ, AG_ATR_WRITE};
+use crate::error::{Error, OpKind, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    Ram,
+    Rom,
+    Flash,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub kind: MemoryKind,
+    pub start: u64,
+    pub length: u64,
+    pub blocksize: Option<u64>,
+}
+
+impl MemoryRegion {
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr - self.start < self.length
+    }
+
+    pub fn end(&self) -> u64 {
+        self.start + self.length
+    }
+}
+
+/// 从 qXfer:memory-map:read 得到的目标内存布局
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub regions: Vec<MemoryRegion>,
+}
+
+fn parse_hex_u64(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let s = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    u64::from_str_radix(s, 16).ok()
+}
+
+fn parse_memory_element(e: &BytesStart) -> Result<Option<MemoryRegion>> {
+    let mut kind = None;
+    let mut start = None;
+    let mut length = None;
+
+    for a in e.attributes().flatten() {
+        let value = String::from_utf8_lossy(a.value.as_ref()).into_owned();
+        match a.key.as_ref() {
+            b"type" => {
+                kind = match value.as_str() {
+                    "ram" => Some(MemoryKind::Ram),
+                    "rom" => Some(MemoryKind::Rom),
+                    "flash" => Some(MemoryKind::Flash),
+                    _ => None,
+                }
+            }
+            b"start" => start = parse_hex_u64(&value),
+            b"length" => length = parse_hex_u64(&value),
+            _ => {}
+        }
+    }
+
+    let (Some(start), Some(length)) = (start, length) else {
+        return Err(Error::Malformed {
+            op: "memory-map".into(),
+            kind: OpKind::Read,
+            reply: "<memory> without valid start/length".into(),
+        });
+    };
+
+    // 未知类型的区域按协议忽略
+    Ok(kind.map(|kind| MemoryRegion {
+        kind,
+        start,
+        length,
+        blocksize: None,
+    }))
+}
+
+impl MemoryMap {
+    pub fn parse(xml: &[u8]) -> Result<Self> {
+        let mut reader = Reader::from_reader(xml);
+        reader.trim_text(true);
+
+        let mut regions = Vec::new();
+        let mut buf = Vec::new();
+        let mut cur: Option<MemoryRegion> = None;
+        let mut in_blocksize = false;
+
+        loop {
+            match reader.read_event_into(&mut buf) {
+                // <memory .../> 没有子元素
+                Ok(Event::Empty(e)) if e.name().as_ref() == b"memory" => {
+                    if let Some(r) = parse_memory_element(&e)? {
+                        regions.push(r);
+                    }
+                }
+
+                Ok(Event::Start(e)) if e.name().as_ref() == b"memory" => {
+                    cur = parse_memory_element(&e)?;
+                }
+
+                Ok(Event::Start(e)) if e.name().as_ref() == b"property" => {
+                    in_blocksize = e
+                        .attributes()
+                        .flatten()
+                        .any(|a| a.key.as_ref() == b"name" && a.value.as_ref() == b"blocksize");
+                }
+
+                Ok(Event::Text(e)) if in_blocksize => {
+                    if let Some(r) = cur.as_mut() {
+                        let txt = String::from_utf8_lossy(e.as_ref()).into_owned();
+                        r.blocksize = parse_hex_u64(&txt);
+                    }
+                }
+
+                Ok(Event::End(e)) if e.name().as_ref() == b"property" => {
+                    in_blocksize = false;
+                }
+
+                Ok(Event::End(e)) if e.name().as_ref() == b"memory" => {
+                    if let Some(r) = cur.take() {
+                        regions.push(r);
+                    }
+                }
+
+                Ok(Event::Eof) => break,
+
+                Err(e) => {
+                    return Err(Error::Malformed {
+                        op: "memory-map".into(),
+                        kind: OpKind::Read,
+                        reply: e.to_string(),
+                    });
+                }
+
+                _ => {}
+            }
+
+            buf.clear();
+        }
+
+        Ok(Self { regions })
+    }
+
+    pub fn region_at(&self, addr: u32) -> Option<&MemoryRegion> {
+        self.regions.iter().find(|r| r.contains(addr as u64))
+    }
+
+    /// 整个范围都落在同一个区域内时才返回该区域
+    pub fn region_for_range(&self, addr: u32, len: u32) -> Option<&MemoryRegion> {
+        let end = addr as u64 + len.max(1) as u64;
+        self.region_at(addr).filter(|r| end <= r.end())
+    }
+
+    /// 按区域边界切分一段地址，返回 (区域, 起始地址, 长度)；有未映射的部分时返回 None
+    ///
+    /// OpenOCD 把每组相同大小的扇区报告为一个区域，一段数据可能跨越多个区域
+    pub fn split_range(&self, addr: u32, len: u32) -> Option<Vec<(&MemoryRegion, u32, u32)>> {
+        let mut pieces = Vec::new();
+        let end = addr as u64 + len as u64;
+        let mut at = addr as u64;
+        while at < end {
+            let region = self.region_at(at as u32)?;
+            let to = region.end().min(end);
+            pieces.push((region, at as u32, (to - at) as u32));
+            at = to;
+        }
+        Some(pieces)
+    }
+
+    pub fn flash_regions(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions.iter().filter(|r| r.kind == MemoryKind::Flash)
+    }
+
+    /// 给 AG_MemAtt 的访问属性：flash / rom 只读，ram 可读写，未映射的地址为 0
+    pub fn attributes(&self, addr: u32, len: u32) -> u32 {
+        match self.region_for_range(addr, len).map(|r| r.kind) {
+            Some(MemoryKind::Ram) => AG_ATR_EXEC | AG_ATR_READ | AG_ATR_WRITE,
+            Some(MemoryKind::Flash | MemoryKind::Rom) => AG_ATR_EXEC | AG_ATR_READ,
+            None => 0,
+        }
+    }
+
+    /// flash / rom 不能写入 BKPT 指令，执行断点只能用硬件比较器
+    pub fn needs_hw_breakpoint(&self, addr: u32) -> bool {
+        !matches!(self.region_at(addr).map(|r| r.kind), Some(MemoryKind::Ram))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &[u8] = br#"<?xml version="1.0"?>
+<!DOCTYPE memory-map PUBLIC "+//IDN gnu.org//DTD GDB Memory Map V1.0//EN" "http://sourceware.org/gdb/gdb-memory-map.dtd">
+<memory-map>
+  <memory type="ram" start="0x00000000" length="0x08000000"/>
+  <memory type="flash" start="0x08000000" length="0x20000">
+    <property name="blocksize">0x800</property>
+  </memory>
+  <memory type="ram" start="0x20000000" length="0x5000"/>
+  <memory type="rom" start="0x1ffff000" length="0x800"/>
+</memory-map>
+"#;
+
+    #[test]
+    fn test_parse_regions() {
+        let map = MemoryMap::parse(SAMPLE).unwrap();
+        assert_eq!(map.regions.len(), 4);
+
+        let flash: Vec<_> = map.flash_regions().collect();
+        assert_eq!(flash.len(), 1);
+        assert_eq!(flash[0].start, 0x0800_0000);
+        assert_eq!(flash[0].length, 0x2_0000);
+        assert_eq!(flash[0].blocksize, Some(0x800));
+
+        assert_eq!(
+            map.region_at(0x2000_0100).map(|r| r.kind),
+            Some(MemoryKind::Ram)
+        );
+        assert_eq!(
+            map.region_at(0x1fff_f400).map(|r| r.kind),
+            Some(MemoryKind::Rom)
+        );
+        assert_eq!(map.region_at(0x4000_0000), None);
+    }
+
+    /// OpenOCD 的映射：flash 前后的空隙报告为 ram
+    #[test]
+    fn test_parse_openocd_flash_region() {
+        let xml = br#"
+<memory-map>
+  <memory type="ram" start="0x00000000" length="0x08000000"/>
+  <memory type="flash" start="0x08000000" length="0x8000">
+    <property name="blocksize">0x400</property>
+  </memory>
+  <memory type="ram" start="0x08008000" length="0xf7ff8000"/>
+</memory-map>
+"#;
+        let map = MemoryMap::parse(xml).unwrap();
+
+        let flash: Vec<_> = map.flash_regions().collect();
+        assert_eq!(flash.len(), 1);
+        assert_eq!(flash[0].start, 0x0800_0000);
+        assert_eq!(flash[0].length, 0x8000);
+        assert_eq!(flash[0].blocksize, Some(0x400));
+        assert_eq!(
+            map.region_at(0xffff_fffc).map(|r| r.kind),
+            Some(MemoryKind::Ram)
+        );
+    }
+
+    #[test]
+    fn test_attributes() {
+        let map = MemoryMap::parse(SAMPLE).unwrap();
+
+        assert_eq!(map.attributes(0x0800_0000, 4), AG_ATR_EXEC | AG_ATR_READ);
+        assert_eq!(
+            map.attributes(0x2000_0000, 0x100),
+            AG_ATR_EXEC | AG_ATR_READ | AG_ATR_WRITE
+        );
+        // 未映射
+        assert_eq!(map.attributes(0x6000_0000, 4), 0);
+        // 跨越 RAM 末尾
+        assert_eq!(map.attributes(0x2000_4ffc, 8), 0);
+    }
+
+    #[test]
+    fn test_split_range() {
+        let map = MemoryMap::parse(SAMPLE).unwrap();
+
+        let pieces = map.split_range(0x07ff_fff0, 0x20).unwrap();
+        let pieces: Vec<_> = pieces.iter().map(|(r, a, l)| (r.kind, *a, *l)).collect();
+        assert_eq!(
+            pieces,
+            vec![
+                (MemoryKind::Ram, 0x07ff_fff0, 0x10),
+                (MemoryKind::Flash, 0x0800_0000, 0x10)
+            ]
+        );
+        assert_eq!(map.split_range(0x0800_0100, 0x100).unwrap().len(), 1);
+        // 跨越 flash 末尾
+        assert!(map.split_range(0x0801_fff0, 0x20).is_none());
+    }
+
+    #[test]
+    fn test_needs_hw_breakpoint() {
+        let map = MemoryMap::parse(SAMPLE).unwrap();
+
+        assert!(map.needs_hw_breakpoint(0x0800_0100));
+        assert!(map.needs_hw_breakpoint(0x1fff_f000));
+        assert!(!map.needs_hw_breakpoint(0x2000_0100));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(
+            MemoryMap::parse(
+                br#"<memory-map><memory type="ram" start="zz" length="0x10"/></memory-map>"#
+            )
+            .is_err()
+        );
+    }
+}
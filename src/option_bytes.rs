@@ -0,0 +1,409 @@
+use crate::error::{Error, Result};
+
+/// 一项选项字节的值：`地址 值 [掩码]`，掩码默认 0xFFFFFFFF
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionByte {
+    pub addr: u32,
+    pub value: u32,
+    pub mask: u32,
+}
+
+pub fn parse_u32(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+impl OptionByte {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split_whitespace();
+        let addr = parse_u32(parts.next()?)?;
+        let value = parse_u32(parts.next()?)?;
+        let mask = match parts.next() {
+            Some(m) => parse_u32(m)?,
+            None => u32::MAX,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self { addr, value, mask })
+    }
+
+    pub fn matches(&self, word: u32) -> bool {
+        word & self.mask == self.value & self.mask
+    }
+}
+
+/// 无法撤销的设置：写入后芯片不能再调试或改写
+struct IrreversibleRule {
+    families: &'static [&'static str],
+    addr: u32,
+    mask: u32,
+    value: u32,
+    what: &'static str,
+}
+
+const IRREVERSIBLE_RULES: &[IrreversibleRule] = &[
+    IrreversibleRule {
+        families: &["stm32f2x", "stm32f4x", "stm32f7x"],
+        addr: 0x1FFF_C000,
+        mask: 0xFF00,
+        value: 0xCC00,
+        what: "RDP level 2",
+    },
+    // FLASH_OPTCR，RDP 在 bit 15:8
+    IrreversibleRule {
+        families: &["stm32f2x", "stm32f4x", "stm32f7x"],
+        addr: 0x4002_3C14,
+        mask: 0xFF00,
+        value: 0xCC00,
+        what: "RDP level 2",
+    },
+    IrreversibleRule {
+        families: &["stm32l4x", "stm32g0x", "stm32g4x", "stm32wbx"],
+        addr: 0x1FFF_7800,
+        mask: 0xFF,
+        value: 0xCC,
+        what: "RDP level 2",
+    },
+    // FLASH_OPTR，RDP 在 bit 7:0
+    IrreversibleRule {
+        families: &["stm32l4x", "stm32g0x", "stm32g4x"],
+        addr: 0x4002_2020,
+        mask: 0xFF,
+        value: 0xCC,
+        what: "RDP level 2",
+    },
+    IrreversibleRule {
+        families: &["stm32wbx"],
+        addr: 0x5800_4020,
+        mask: 0xFF,
+        value: 0xCC,
+        what: "RDP level 2",
+    },
+];
+
+/// OTP 区域：写入后不能擦除
+struct OtpRange {
+    families: &'static [&'static str],
+    start: u32,
+    end: u32,
+}
+
+const OTP_RANGES: &[OtpRange] = &[
+    OtpRange {
+        families: &["stm32f2x", "stm32f4x", "stm32f7x"],
+        start: 0x1FFF_7800,
+        end: 0x1FFF_7A10,
+    },
+    OtpRange {
+        families: &["stm32l4x", "stm32g0x", "stm32g4x", "stm32wbx"],
+        start: 0x1FFF_7000,
+        end: 0x1FFF_7400,
+    },
+];
+
+/// 不改变选项字节或只改变可以撤销的设置（BOR、看门狗、RDP level 1 等）的子命令
+const REVERSIBLE_SUBCOMMANDS: &[&str] = &[
+    "options_read",
+    "options_write",
+    "option_read",
+    "option_load",
+    "lock",
+    "unlock",
+];
+
+/// 不需要 flash 驱动名的通用命令
+const REVERSIBLE_COMMANDS: &[&str] = &["reset", "halt", "sleep", "mdw", "mdh", "mdb"];
+
+/// apply 中一条 monitor 命令的效果
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Effect {
+    Reversible,
+    Irreversible(String),
+    /// 无法判断，需要单独确认这条命令
+    Unknown,
+}
+
+/// `[option_bytes]` 段：下载完成后写入并回读校验
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OptionBytes {
+    /// OpenOCD 的 flash 驱动名，例如 stm32f4x，用于识别不可逆的设置
+    pub family: Option<String>,
+    /// 写入选项字节的 monitor 命令；不可逆的需要 allow_irreversible，
+    /// 无法判断的需要在 confirm 中逐条确认
+    pub apply: Vec<String>,
+    /// 单独确认过的 apply 命令
+    pub confirm: Vec<String>,
+    /// 直接写入寄存器的值
+    pub write: Vec<OptionByte>,
+    /// 写入 OTP 区域的字，总是不可逆
+    pub otp: Vec<OptionByte>,
+    /// 写入后回读校验的值
+    pub verify: Vec<OptionByte>,
+    pub allow_irreversible: bool,
+}
+
+impl OptionBytes {
+    pub fn is_empty(&self) -> bool {
+        self.apply.is_empty()
+            && self.write.is_empty()
+            && self.otp.is_empty()
+            && self.verify.is_empty()
+    }
+
+    /// 未配置 family 时按所有系列检查
+    fn for_family(&self, families: &[&str]) -> bool {
+        self.family.as_deref().is_none_or(|f| families.contains(&f))
+    }
+
+    /// 写入一个字会不会造成不可逆的设置
+    fn irreversible_write(&self, addr: u32, value: u32) -> Option<String> {
+        if let Some(rule) = IRREVERSIBLE_RULES
+            .iter()
+            .filter(|r| self.for_family(r.families))
+            .find(|r| r.addr == addr && value & r.mask == r.value)
+        {
+            return Some(format!("{} at {:#010x}", rule.what, addr));
+        }
+        OTP_RANGES
+            .iter()
+            .filter(|r| self.for_family(r.families))
+            .any(|r| (r.start..r.end).contains(&addr))
+            .then(|| format!("OTP write at {:#010x}", addr))
+    }
+
+    /// 按已知的 OpenOCD 命令和参数判断 monitor 命令的效果
+    fn classify(&self, cmd: &str) -> Effect {
+        let args: Vec<&str> = cmd.split_whitespace().collect();
+        let effect = |irreversible: Option<String>| match irreversible {
+            Some(what) => Effect::Irreversible(format!("{} (monitor `{}`)", what, cmd)),
+            None => Effect::Reversible,
+        };
+        match args.as_slice() {
+            [c, ..] if REVERSIBLE_COMMANDS.contains(c) => Effect::Reversible,
+            ["mww", addr, value] => match (parse_u32(addr), parse_u32(value)) {
+                (Some(addr), Some(value)) => effect(self.irreversible_write(addr, value)),
+                _ => Effect::Unknown,
+            },
+            // stm32l4x option_write <bank> <reg_offset> <value> [mask]，FLASH_OPTR 的 RDP 在 bit 7:0
+            [driver, "option_write", _, offset, value, rest @ ..]
+                if driver.starts_with("stm32") && rest.len() <= 1 =>
+            {
+                let mask = rest.first().map_or(Some(u32::MAX), |m| parse_u32(m));
+                match (parse_u32(offset), parse_u32(value), mask) {
+                    (Some(0x20), Some(value), Some(mask)) => effect(
+                        (mask & 0xFF == 0xFF && value & 0xFF == 0xCC)
+                            .then(|| "RDP level 2".to_string()),
+                    ),
+                    (Some(_), Some(_), Some(_)) => Effect::Reversible,
+                    _ => Effect::Unknown,
+                }
+            }
+            // stm32f2x otp <bank> enable 之后写入的数据不能再擦除
+            [driver, "otp", _, mode] if driver.starts_with("stm32") => {
+                effect((*mode == "enable").then(|| "OTP write enable".to_string()))
+            }
+            [driver, sub, ..]
+                if driver.starts_with("stm32") && REVERSIBLE_SUBCOMMANDS.contains(sub) =>
+            {
+                Effect::Reversible
+            }
+            _ => Effect::Unknown,
+        }
+    }
+
+    /// 无法判断效果、也没有在 confirm 中确认的 apply 命令
+    pub fn unconfirmed(&self) -> Vec<&str> {
+        self.apply
+            .iter()
+            .filter(|c| self.classify(c) == Effect::Unknown && !self.confirm.contains(c))
+            .map(|c| c.as_str())
+            .collect()
+    }
+
+    /// 列出配置中所有不可逆的设置
+    pub fn irreversible(&self) -> Vec<String> {
+        let mut found: Vec<String> = self
+            .otp
+            .iter()
+            .map(|o| format!("OTP write at {:#010x}", o.addr))
+            .collect();
+        for cmd in &self.apply {
+            if let Effect::Irreversible(what) = self.classify(cmd) {
+                found.push(what);
+            }
+        }
+
+        for rule in IRREVERSIBLE_RULES
+            .iter()
+            .filter(|r| self.for_family(r.families))
+        {
+            // write 写入整个字，掩码只用于回读校验
+            let written = self.write.iter().filter(|o| o.addr == rule.addr);
+            let verified = self
+                .verify
+                .iter()
+                .filter(|o| o.addr == rule.addr && o.mask & rule.mask == rule.mask);
+            let hit = written
+                .chain(verified)
+                .any(|o| o.value & rule.mask == rule.value);
+            let entry = format!("{} at {:#010x}", rule.what, rule.addr);
+            if hit && !found.contains(&entry) {
+                found.push(entry);
+            }
+        }
+        found
+    }
+
+    /// 未在配置中确认时拒绝不可逆的设置和无法判断的命令
+    pub fn check(&self) -> Result<()> {
+        // allow_irreversible 不能代替逐条确认
+        if let Some(cmd) = self.unconfirmed().first() {
+            return Err(Error::Refused {
+                action: format!("monitor `{}` with unknown effect on option bytes", cmd),
+                hint: format!("add `confirm = {}` to [option_bytes] to run it", cmd),
+            });
+        }
+
+        let irreversible = self.irreversible();
+        if irreversible.is_empty() || self.allow_irreversible {
+            return Ok(());
+        }
+        Err(Error::Refused {
+            action: format!("irreversible option bytes ({})", irreversible.join(", ")),
+            hint: "set [option_bytes] allow_irreversible = yes to confirm".into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_option_byte() {
+        assert_eq!(
+            OptionByte::parse("0x1FFFC000 0xAAED 0xFFFF"),
+            Some(OptionByte {
+                addr: 0x1fff_c000,
+                value: 0xaaed,
+                mask: 0xffff
+            })
+        );
+        assert_eq!(
+            OptionByte::parse("0x40023C14 16").map(|o| (o.value, o.mask)),
+            Some((16, u32::MAX))
+        );
+        assert_eq!(OptionByte::parse("0x1FFFC000"), None);
+        assert_eq!(OptionByte::parse("0x1FFFC000 zz"), None);
+        assert_eq!(OptionByte::parse("1 2 3 4"), None);
+    }
+
+    #[test]
+    fn test_matches() {
+        let ob = OptionByte::parse("0x1FFFC000 0xAA00 0xFF00").unwrap();
+        assert!(ob.matches(0x0fff_aaed));
+        assert!(!ob.matches(0x0fff_bbed));
+    }
+
+    #[test]
+    fn test_irreversible() {
+        let mut ob = OptionBytes {
+            family: Some("stm32f4x".into()),
+            verify: vec![OptionByte::parse("0x1FFFC000 0xCCED 0xFFFF").unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(ob.irreversible().len(), 1);
+        assert!(ob.check().is_err());
+
+        ob.allow_irreversible = true;
+        assert!(ob.check().is_ok());
+
+        // 其他系列没有这个规则
+        ob.family = Some("stm32f1x".into());
+        ob.allow_irreversible = false;
+        assert!(ob.check().is_ok());
+
+        // RDP level 1 可以撤销
+        ob.family = Some("stm32f4x".into());
+        ob.verify = vec![OptionByte::parse("0x1FFFC000 0x55ED 0xFFFF").unwrap()];
+        assert!(ob.check().is_ok());
+
+        ob.otp = vec![OptionByte::parse("0x1FFF7800 0x12345678").unwrap()];
+        assert!(ob.check().is_err());
+    }
+
+    #[test]
+    fn test_irreversible_register_write() {
+        // FLASH_OPTCR 的 RDP 字段为 0xCC
+        let mut ob = OptionBytes {
+            family: Some("stm32f4x".into()),
+            write: vec![OptionByte::parse("0x40023C14 0x0FFFCCEC").unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(ob.irreversible(), vec!["RDP level 2 at 0x40023c14"]);
+        assert!(ob.check().is_err());
+
+        ob.write = vec![OptionByte::parse("0x40023C14 0x0FFFAAEC").unwrap()];
+        assert!(ob.check().is_ok());
+
+        // 未配置 family 时同样检查
+        ob.family = None;
+        ob.write = vec![OptionByte::parse("0x40022020 0xFFEFF8CC").unwrap()];
+        assert!(ob.check().is_err());
+    }
+
+    #[test]
+    fn test_apply_classification() {
+        let mut ob = OptionBytes {
+            family: Some("stm32l4x".into()),
+            apply: vec![
+                // BOR、看门狗等用户选项不需要确认
+                "stm32f2x options_write 0 0xEC".into(),
+                "stm32l4x option_write 0 0x20 0x0EEFF8AA".into(),
+                "stm32l4x option_load 0".into(),
+                "reset halt".into(),
+            ],
+            ..Default::default()
+        };
+        assert!(ob.irreversible().is_empty());
+        assert!(ob.check().is_ok());
+
+        ob.apply = vec!["stm32l4x option_write 0 0x20 0xCC 0xFF".into()];
+        assert_eq!(
+            ob.irreversible(),
+            vec!["RDP level 2 (monitor `stm32l4x option_write 0 0x20 0xCC 0xFF`)"]
+        );
+        assert!(ob.check().is_err());
+        ob.allow_irreversible = true;
+        assert!(ob.check().is_ok());
+
+        ob.family = Some("stm32f4x".into());
+        ob.allow_irreversible = false;
+        for cmd in ["stm32f2x otp 0 enable", "mww 0x1FFF7800 0x12345678"] {
+            ob.apply = vec![cmd.into()];
+            assert_eq!(ob.irreversible().len(), 1, "{}", cmd);
+        }
+        ob.apply = vec!["mww 0x40023C14 0x0FFFAAEC".into()];
+        assert!(ob.irreversible().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_apply_needs_own_confirmation() {
+        let mut ob = OptionBytes {
+            apply: vec!["my_option_proc 0xCC".into(), "reset halt".into()],
+            allow_irreversible: true,
+            ..Default::default()
+        };
+        // allow_irreversible 不能代替逐条确认
+        let err = ob.check().unwrap_err();
+        assert!(matches!(err, Error::Refused { .. }));
+        assert!(err.to_string().contains("confirm = my_option_proc 0xCC"));
+
+        ob.confirm = vec!["my_option_proc 0xCC".into()];
+        ob.allow_irreversible = false;
+        assert!(ob.check().is_ok());
+    }
+}
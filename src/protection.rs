@@ -0,0 +1,133 @@
+use crate::error::{Error, Result};
+use crate::gdb_client::{GdbClient, GdbTransport};
+use crate::option_bytes::OptionByte;
+
+/// 判断芯片是否处于读保护的方法
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtectionCheck {
+    /// 读取寄存器，`(值 & 掩码) == 期望值` 时表示已保护
+    Register(OptionByte),
+    /// 执行 monitor 命令，输出中包含 pattern 时表示已保护
+    Monitor { cmd: String, pattern: String },
+}
+
+/// `[protection]` 段
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Protection {
+    pub check: Option<ProtectionCheck>,
+    /// 解除保护的 monitor 命令，通常会整片擦除
+    pub unlock: Vec<String>,
+    pub allow_unlock: bool,
+}
+
+impl Protection {
+    /// 配置了解除保护的命令并且允许执行
+    pub fn can_unlock(&self) -> bool {
+        self.allow_unlock && !self.unlock.is_empty()
+    }
+
+    /// 没有配置检查方法时认为未保护
+    pub fn is_protected<T: GdbTransport>(&self, client: &mut GdbClient<T>) -> Result<bool> {
+        match &self.check {
+            None => Ok(false),
+            Some(ProtectionCheck::Register(reg)) => Ok(reg.matches(client.read_u32(reg.addr)?)),
+            Some(ProtectionCheck::Monitor { cmd, pattern }) => {
+                let output = client.monitor(cmd)?;
+                Ok(output
+                    .to_ascii_lowercase()
+                    .contains(&pattern.to_ascii_lowercase()))
+            }
+        }
+    }
+
+    /// 解除保护后重新检查，仍处于保护状态时返回错误
+    pub fn unlock<T: GdbTransport>(&self, client: &mut GdbClient<T>) -> Result<()> {
+        if !self.can_unlock() {
+            return Err(Error::Refused {
+                action: "to unlock the read-out protected chip".into(),
+                hint: "set [protection] allow_unlock = yes and unlock = <monitor command> \
+                       to mass-erase and unlock it"
+                    .into(),
+            });
+        }
+
+        for cmd in &self.unlock {
+            client.monitor(cmd)?;
+        }
+
+        if self.is_protected(client)? {
+            return Err(Error::Monitor {
+                cmd: self.unlock.join("; "),
+                reply: "OK".into(),
+                output: "the chip is still read-out protected after unlock".into(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gdb_client::MockTransport;
+
+    fn client(responses: &[&[u8]]) -> GdbClient<MockTransport> {
+        let mut v = Vec::new();
+        for r in responses {
+            v.push(vec![b'+']);
+            v.push(MockTransport::rsp_packet(r));
+        }
+        GdbClient::new(MockTransport::new(v, true))
+    }
+
+    #[test]
+    fn test_register_check() {
+        let p = Protection {
+            check: Some(ProtectionCheck::Register(
+                OptionByte::parse("0x4002201C 0x2 0x2").unwrap(),
+            )),
+            ..Default::default()
+        };
+
+        assert!(p.is_protected(&mut client(&[b"02000000"])).unwrap());
+        assert!(!p.is_protected(&mut client(&[b"fcffffff"])).unwrap());
+        assert!(
+            !Protection::default()
+                .is_protected(&mut client(&[]))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_monitor_check() {
+        let p = Protection {
+            check: Some(ProtectionCheck::Monitor {
+                cmd: "stm32f1x options_read 0".into(),
+                pattern: "read protection: on".into(),
+            }),
+            ..Default::default()
+        };
+
+        // "Read Protection: on\n"
+        let mut c = client(&[b"O526561642050726f74656374696f6e3a206f6e0a", b"OK"]);
+        assert!(p.is_protected(&mut c).unwrap());
+    }
+
+    #[test]
+    fn test_unlock() {
+        let mut p = Protection {
+            check: Some(ProtectionCheck::Register(
+                OptionByte::parse("0x4002201C 0x2 0x2").unwrap(),
+            )),
+            unlock: vec!["stm32f1x unlock 0".into()],
+            allow_unlock: false,
+        };
+
+        let err = p.unlock(&mut client(&[])).unwrap_err();
+        assert!(matches!(err, Error::Refused { .. }));
+
+        p.allow_unlock = true;
+        p.unlock(&mut client(&[b"OK", b"00000000"])).unwrap();
+        assert!(p.unlock(&mut client(&[b"OK", b"02000000"])).is_err());
+    }
+}
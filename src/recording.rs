@@ -0,0 +1,245 @@
+//! 记录与 GDB 服务器之间的全部数据，用于复现现场的问题
+//!
+//! 每行一条记录：`<秒数> <方向> <数据>`，方向 `>` 为发送，`<` 为接收，
+//! `!` 为读写错误，`*` 为连接事件；数据用 `escape_ascii` 转义。
+//! 连续的接收数据合并为一行，时间为其中第一个字节到达的时间；
+//! 目标运行时每次轮询都会读超时，连续的超时只记录第一次。
+
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::gdb_client::GdbTransport;
+
+pub const DIR_SEND: char = '>';
+pub const DIR_RECV: char = '<';
+pub const DIR_ERROR: char = '!';
+pub const DIR_EVENT: char = '*';
+
+/// 包装任意 transport，sink 为 None 时不做任何记录
+pub struct RecordingTransport<T: GdbTransport> {
+    inner: T,
+    sink: Option<Box<dyn Write + Send>>,
+    start: Instant,
+    /// 尚未写出的接收数据及其开始时间
+    recv: Vec<u8>,
+    recv_at: Duration,
+    /// 上一条记录是读超时
+    timed_out: bool,
+}
+
+impl<T: GdbTransport> RecordingTransport<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            sink: None,
+            start: Instant::now(),
+            recv: Vec::new(),
+            recv_at: Duration::ZERO,
+            timed_out: false,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_sink(inner: T, sink: Box<dyn Write + Send>) -> Self {
+        let mut rec = Self::new(inner);
+        rec.set_sink(sink);
+        rec
+    }
+
+    fn set_sink(&mut self, sink: Box<dyn Write + Send>) {
+        self.sink = Some(sink);
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.comment(&format!("openocd_agdi recording, unix time {}", started));
+    }
+
+    /// 追加到文件末尾，同一个文件可以保存多次会话
+    pub fn record_to(&mut self, path: &Path) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.set_sink(Box::new(file));
+        Ok(())
+    }
+
+    fn comment(&mut self, text: &str) {
+        if let Some(sink) = self.sink.as_mut() {
+            let _ = writeln!(sink, "# {}", text);
+        }
+    }
+
+    fn write_line(&mut self, at: Duration, dir: char, data: &[u8]) {
+        if let Some(sink) = self.sink.as_mut() {
+            // 记录失败不影响调试
+            let _ = writeln!(
+                sink,
+                "{:12.6} {} {}",
+                at.as_secs_f64(),
+                dir,
+                data.escape_ascii()
+            );
+        }
+    }
+
+    fn flush_recv(&mut self) {
+        if !self.recv.is_empty() {
+            let data = std::mem::take(&mut self.recv);
+            self.write_line(self.recv_at, DIR_RECV, &data);
+        }
+        if let Some(sink) = self.sink.as_mut() {
+            let _ = sink.flush();
+        }
+    }
+
+    fn record(&mut self, dir: char, data: &[u8]) {
+        if self.sink.is_none() {
+            return;
+        }
+        self.flush_recv();
+        self.write_line(self.start.elapsed(), dir, data);
+        self.timed_out = false;
+    }
+
+    fn record_result<R>(&mut self, result: io::Result<R>) -> io::Result<R> {
+        if let Err(e) = &result {
+            let timeout = is_timeout(e);
+            if !(timeout && self.timed_out) {
+                self.record(DIR_ERROR, format!("{:?}: {}", e.kind(), e).as_bytes());
+            }
+            self.timed_out = timeout;
+        }
+        result
+    }
+}
+
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+impl<T: GdbTransport> GdbTransport for RecordingTransport<T> {
+    fn connect(&mut self) -> io::Result<()> {
+        self.record(DIR_EVENT, b"connect");
+        let result = self.inner.connect();
+        self.record_result(result)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.record(DIR_EVENT, b"close");
+        let result = self.inner.close();
+        self.flush_recv();
+        result
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.record(DIR_SEND, data);
+        let result = self.inner.send(data);
+        self.record_result(result)
+    }
+
+    fn recv_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let result = self.inner.recv_exact(buf);
+        if result.is_ok() && self.sink.is_some() {
+            if self.recv.is_empty() {
+                self.recv_at = self.start.elapsed();
+            }
+            self.recv.extend_from_slice(buf);
+            self.timed_out = false;
+        }
+        self.record_result(result)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+impl<T: GdbTransport> Drop for RecordingTransport<T> {
+    fn drop(&mut self) {
+        self.flush_recv();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_server::{FLASH_BASE, FakeServer};
+    use crate::gdb_client::{GdbClient, MockTransport};
+    use crate::replay_transport::{SharedBuf, parse_line};
+
+    fn lines(buf: &SharedBuf) -> Vec<(char, Vec<u8>)> {
+        buf.text()
+            .lines()
+            .filter_map(|l| parse_line(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_records_both_directions() {
+        let buf = SharedBuf::default();
+        let mock = MockTransport::new(vec![vec![b'+'], MockTransport::rsp_packet(b"OK")], true);
+        let mut client = GdbClient::new(RecordingTransport::with_sink(mock, Box::new(buf.clone())));
+
+        client.flash_done().unwrap();
+        drop(client);
+
+        assert_eq!(
+            lines(&buf),
+            vec![
+                ('>', b"$vFlashDone#ea".to_vec()),
+                ('<', b"+$OK#9a".to_vec()),
+                ('>', b"+".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_records_errors() {
+        let buf = SharedBuf::default();
+        let mock = MockTransport::new(vec![], true);
+        let mut client = GdbClient::new(RecordingTransport::with_sink(mock, Box::new(buf.clone())));
+
+        assert!(client.flash_done().is_err());
+        drop(client);
+
+        let lines = lines(&buf);
+        assert_eq!(lines[0].0, '>');
+        assert_eq!(lines[1].0, '!');
+    }
+
+    #[test]
+    fn test_merges_poll_timeouts() {
+        let server = FakeServer::start();
+        let buf = SharedBuf::default();
+        let mut client = GdbClient::new(RecordingTransport::with_sink(
+            server.transport(),
+            Box::new(buf.clone()),
+        ));
+        client.connect().unwrap();
+        for _ in 0..5 {
+            assert!(
+                client
+                    .poll_stop_reply(Duration::from_millis(1))
+                    .unwrap()
+                    .is_none()
+            );
+        }
+        client.read_u32(FLASH_BASE).unwrap();
+        assert!(
+            client
+                .poll_stop_reply(Duration::from_millis(1))
+                .unwrap()
+                .is_none()
+        );
+        drop(client);
+
+        let dirs: Vec<char> = lines(&buf).iter().map(|l| l.0).collect();
+        assert_eq!(dirs.iter().filter(|&&d| d == DIR_ERROR).count(), 2);
+        assert_eq!(dirs.last(), Some(&DIR_ERROR));
+    }
+}
@@ -0,0 +1,423 @@
+//! 回放 RecordingTransport 的记录文件：检查客户端发送的数据与记录一致，
+//! 按记录返回接收的数据和错误，用于离线复现现场的问题。
+//! 记录中连续的读超时只有一行，回放时它一直有效，直到客户端再次发送。
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::gdb_client::GdbTransport;
+use crate::recording::{DIR_ERROR, DIR_EVENT, DIR_RECV, DIR_SEND, is_timeout};
+
+/// `escape_ascii` 的逆操作
+pub fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        out.push(match bytes.next()? {
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            c @ (b'\\' | b'\'' | b'"') => c,
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
+/// 解析记录文件的一行，注释和空行返回 None
+pub fn parse_line(line: &str) -> io::Result<Option<(char, Vec<u8>)>> {
+    let line = line.trim_start();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad recording line: {}", line),
+        )
+    };
+
+    let mut parts = line.splitn(3, ' ');
+    parts
+        .next()
+        .and_then(|t| t.parse::<f64>().ok())
+        .ok_or_else(invalid)?;
+    let dir = parts
+        .next()
+        .and_then(|d| d.chars().next())
+        .filter(|d| [DIR_SEND, DIR_RECV, DIR_ERROR, DIR_EVENT].contains(d))
+        .ok_or_else(invalid)?;
+    let data = unescape(parts.next().unwrap_or("")).ok_or_else(invalid)?;
+    Ok(Some((dir, data)))
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayTransport {
+    records: VecDeque<(char, Vec<u8>)>,
+    /// 第一条记录中已经使用的字节数
+    pos: usize,
+}
+
+/// 记录中的 `Kind: message` 还原为 io::Error
+fn recorded_error(data: &[u8]) -> io::Error {
+    let text = String::from_utf8_lossy(data);
+    let (kind, msg) = text.split_once(": ").unwrap_or(("Other", &text));
+    let kind = match kind {
+        "TimedOut" => io::ErrorKind::TimedOut,
+        "WouldBlock" => io::ErrorKind::WouldBlock,
+        "UnexpectedEof" => io::ErrorKind::UnexpectedEof,
+        "ConnectionReset" => io::ErrorKind::ConnectionReset,
+        "ConnectionAborted" => io::ErrorKind::ConnectionAborted,
+        "ConnectionRefused" => io::ErrorKind::ConnectionRefused,
+        "NotConnected" => io::ErrorKind::NotConnected,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("replayed: {}", msg))
+}
+
+impl ReplayTransport {
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut records = VecDeque::new();
+        for line in text.lines() {
+            if let Some(record) = parse_line(line)? {
+                records.push_back(record);
+            }
+        }
+        Ok(Self { records, pos: 0 })
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn skip_events(&mut self) {
+        while self.records.front().is_some_and(|(d, _)| *d == DIR_EVENT) {
+            self.records.pop_front();
+        }
+    }
+
+    /// 读超时留给下一次接收，不作为发送的错误
+    fn take_error(&mut self) -> Option<io::Error> {
+        self.skip_events();
+        match self.records.front() {
+            Some((DIR_ERROR, e)) if !is_timeout(&recorded_error(e)) => {
+                self.records.pop_front().map(|(_, e)| recorded_error(&e))
+            }
+            _ => None,
+        }
+    }
+
+    /// 客户端重新发送时，之前的读超时已经结束
+    fn skip_timeouts(&mut self) {
+        self.skip_events();
+        while self
+            .records
+            .front()
+            .is_some_and(|(d, e)| *d == DIR_ERROR && is_timeout(&recorded_error(e)))
+        {
+            self.records.pop_front();
+            self.skip_events();
+        }
+    }
+}
+
+impl GdbTransport for ReplayTransport {
+    fn connect(&mut self) -> io::Result<()> {
+        match self.take_error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.skip_events();
+        Ok(())
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.skip_timeouts();
+        let Some((DIR_SEND, recorded)) = self.records.front() else {
+            panic!(
+                "client sent {} but the recording expects {:?}",
+                data.escape_ascii(),
+                self.records
+                    .front()
+                    .map(|(d, r)| format!("{} {}", d, r.escape_ascii()))
+            );
+        };
+        let expected = &recorded[self.pos..];
+        if !expected.starts_with(data) {
+            panic!(
+                "client diverged from the recording\n  expected: {}\n       got: {}",
+                expected.escape_ascii(),
+                data.escape_ascii()
+            );
+        }
+
+        self.pos += data.len();
+        if self.pos == recorded.len() {
+            self.records.pop_front();
+            self.pos = 0;
+        }
+        match self.take_error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn recv_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            if let Some(e) = self.take_error() {
+                return Err(e);
+            }
+            let recorded = match self.records.front() {
+                Some((DIR_RECV, recorded)) => recorded,
+                Some((DIR_ERROR, e)) => return Err(recorded_error(e)),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "client is waiting for data the recording does not contain",
+                    ));
+                }
+            };
+
+            let n = usize::min(buf.len() - filled, recorded.len() - self.pos);
+            buf[filled..filled + n].copy_from_slice(&recorded[self.pos..self.pos + n]);
+            filled += n;
+            self.pos += n;
+            if self.pos == recorded.len() {
+                self.records.pop_front();
+                self.pos = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// 记录到内存，transport drop 之后仍然可以读取
+#[derive(Clone, Default)]
+pub struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuf {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agdi_consts::{AG_GOFORBRK, AG_OK, AG_STOPRUN};
+    use crate::agdi_impl::Agdi;
+    use crate::config::Config;
+    use crate::fake_server::{FLASH_BASE, FakeServer, Fault};
+    use crate::gdb_client::GdbClient;
+    use crate::host::{KeilHost, NullHost, RecordingNotifier};
+    use crate::host_sim::HostSim;
+    use crate::recording::RecordingTransport;
+
+    #[test]
+    fn test_unescape_roundtrip() {
+        let data: Vec<u8> = (0..=255).collect();
+        let escaped = data.escape_ascii().to_string();
+        assert_eq!(unescape(&escaped), Some(data));
+        assert_eq!(unescape("\\q"), None);
+        assert_eq!(unescape("\\x4"), None);
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(parse_line("# comment").unwrap(), None);
+        assert_eq!(
+            parse_line("    0.000125 > $g#67").unwrap(),
+            Some(('>', b"$g#67".to_vec()))
+        );
+        assert_eq!(
+            parse_line("    0.5 < + $O 1\\x03").unwrap(),
+            Some(('<', b"+ $O 1\x03".to_vec()))
+        );
+        assert!(parse_line("0.1 ? x").is_err());
+        assert!(parse_line("abc > x").is_err());
+    }
+
+    #[test]
+    fn test_replay_client_session() {
+        let server = FakeServer::start();
+        let buf = SharedBuf::default();
+        let mut client = GdbClient::new(RecordingTransport::with_sink(
+            server.transport(),
+            Box::new(buf.clone()),
+        ));
+        client.connect().unwrap();
+        client.flash_erase(FLASH_BASE, 0x400).unwrap();
+        client.flash_write(FLASH_BASE, &[1, 2, 3, 4], 256).unwrap();
+        client.flash_done().unwrap();
+        let word = client.read_u32(FLASH_BASE).unwrap();
+        drop(client);
+
+        let replay = ReplayTransport::parse(&buf.text()).unwrap();
+        let mut client = GdbClient::new(replay);
+        client.connect().unwrap();
+        client.flash_erase(FLASH_BASE, 0x400).unwrap();
+        client.flash_write(FLASH_BASE, &[1, 2, 3, 4], 256).unwrap();
+        client.flash_done().unwrap();
+        assert_eq!(client.read_u32(FLASH_BASE).unwrap(), word);
+    }
+
+    #[test]
+    fn test_replay_from_file() {
+        let server = FakeServer::start();
+        let path = std::env::temp_dir().join(format!("openocd_agdi_{}.rec", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut transport = RecordingTransport::new(server.transport());
+        transport.record_to(&path).unwrap();
+        let mut client = GdbClient::new(transport);
+        client.connect().unwrap();
+        client.monitor("reset halt").unwrap();
+        drop(client);
+
+        let mut client = GdbClient::new(ReplayTransport::from_file(&path).unwrap());
+        let _ = std::fs::remove_file(&path);
+        client.connect().unwrap();
+        client.monitor("reset halt").unwrap();
+    }
+
+    #[test]
+    fn test_replay_recorded_error() {
+        let log = "\
+# openocd_agdi recording
+    0.000010 * connect
+    0.000200 > $vFlashErase:8020000,400#a8
+    0.001500 < +$E04#a9
+    0.001600 > +
+    0.002000 > $vFlashDone#ea
+    2.002000 ! TimedOut: read timed out
+";
+        let mut client = GdbClient::new(ReplayTransport::parse(log).unwrap());
+        client.connect().unwrap();
+        assert!(client.flash_erase(0x0802_0000, 0x400).is_err());
+
+        let err = client.flash_done().unwrap_err();
+        assert!(err.is_timeout());
+        assert_eq!(err.to_string(), "timed out waiting for reply to vFlashDone");
+    }
+
+    #[test]
+    #[should_panic(expected = "client diverged from the recording")]
+    fn test_replay_divergence_panics() {
+        let log = "0.1 > $vFlashDone#ea\n";
+        let mut client = GdbClient::new(ReplayTransport::parse(log).unwrap());
+        client.connect().unwrap();
+        let _ = client.flash_erase(FLASH_BASE, 0x400);
+    }
+
+    /// 现场的下载失败：记录一次，之后不需要服务器也能复现
+    #[test]
+    fn test_replay_failed_download() {
+        let server = FakeServer::start();
+        server
+            .target()
+            .inject("vFlashWrite", Fault::Reply("E04"), 1);
+        let buf = SharedBuf::default();
+        let image = [0x5au8; 16];
+
+        let status = {
+            let host = HostSim::new(&[(FLASH_BASE, &image)]);
+            let mut agdi = Agdi::with_parts(
+                Config::default(),
+                RecordingTransport::with_sink(server.transport(), Box::new(buf.clone())),
+                KeilHost::default(),
+                RecordingNotifier::default(),
+            );
+            host.download(&mut agdi)
+        };
+        assert_ne!(status, AG_OK);
+        drop(server);
+
+        let host = HostSim::new(&[(FLASH_BASE, &image)]);
+        let mut agdi = Agdi::with_parts(
+            Config::default(),
+            ReplayTransport::parse(&buf.text()).unwrap(),
+            KeilHost::default(),
+            RecordingNotifier::default(),
+        );
+        assert_eq!(host.download(&mut agdi), status);
+        assert!(
+            host.messages()
+                .iter()
+                .any(|m| m.starts_with("Download failed"))
+        );
+    }
+
+    /// 运行期间的每次轮询都会读超时，回放时不需要相同的轮询次数
+    #[test]
+    fn test_replay_run_session() {
+        let server = FakeServer::start();
+        let buf = SharedBuf::default();
+        let null = std::ptr::null_mut();
+        let poll = Duration::from_millis(5);
+
+        let mut agdi = Agdi::with_parts(
+            Config::default(),
+            RecordingTransport::with_sink(server.transport(), Box::new(buf.clone())),
+            NullHost,
+            RecordingNotifier::default(),
+        );
+        assert_eq!(agdi.init_features(), AG_OK);
+        assert_eq!(agdi.go_begin(AG_GOFORBRK, 0, null), None);
+        for _ in 0..10 {
+            assert_eq!(agdi.go_poll(null, poll), None);
+        }
+        assert_eq!(agdi.go_begin(AG_STOPRUN, 0, null), Some(AG_OK));
+        assert_eq!(agdi.go_poll(null, poll), Some(AG_OK));
+        drop(agdi);
+        drop(server);
+
+        let text = buf.text();
+        let timeouts = text.lines().filter(|l| l.contains(" ! ")).count();
+        assert_eq!(timeouts, 1, "{}", text);
+
+        let mut agdi = Agdi::with_parts(
+            Config::default(),
+            ReplayTransport::parse(&text).unwrap(),
+            NullHost,
+            RecordingNotifier::default(),
+        );
+        assert_eq!(agdi.init_features(), AG_OK);
+        assert_eq!(agdi.go_begin(AG_GOFORBRK, 0, null), None);
+        for _ in 0..3 {
+            assert_eq!(agdi.go_poll(null, poll), None);
+        }
+        assert_eq!(agdi.go_begin(AG_STOPRUN, 0, null), Some(AG_OK));
+        assert_eq!(agdi.go_poll(null, poll), Some(AG_OK));
+    }
+}
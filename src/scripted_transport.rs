@@ -0,0 +1,225 @@
+//! 按脚本逐包检查的 transport：每一步给出期望收到的包和要回复的包，
+//! 收到不符合脚本的包时立即 panic 并打印差异
+
+use std::collections::VecDeque;
+use std::io;
+
+use crate::gdb_client::{GdbTransport, MockTransport};
+
+#[derive(Debug)]
+enum Expect {
+    Exact(Vec<u8>),
+    Prefix(Vec<u8>),
+    Interrupt,
+}
+
+#[derive(Debug)]
+struct Step {
+    expect: Expect,
+    nack: bool,
+    replies: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+pub struct ScriptedTransport {
+    steps: VecDeque<Step>,
+    received: usize,
+    pending: VecDeque<u8>,
+    connected: bool,
+}
+
+fn show(data: &[u8]) -> String {
+    data.escape_ascii().to_string()
+}
+
+impl ScriptedTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn step(mut self, expect: Expect, nack: bool, replies: &[&str]) -> Self {
+        self.steps.push_back(Step {
+            expect,
+            nack,
+            replies: replies.iter().map(|r| r.as_bytes().to_vec()).collect(),
+        });
+        self
+    }
+
+    /// 期望完全相同的包，按顺序回复 replies
+    pub fn expect(self, packet: &str, replies: &[&str]) -> Self {
+        self.step(Expect::Exact(packet.as_bytes().to_vec()), false, replies)
+    }
+
+    /// 只比较开头，用于带二进制数据的包
+    pub fn expect_prefix(self, prefix: &str, replies: &[&str]) -> Self {
+        self.step(Expect::Prefix(prefix.as_bytes().to_vec()), false, replies)
+    }
+
+    /// 期望 Ctrl-C
+    pub fn expect_interrupt(self, replies: &[&str]) -> Self {
+        self.step(Expect::Interrupt, false, replies)
+    }
+
+    /// 期望这个包并回复 `-`
+    pub fn nack(self, packet: &str) -> Self {
+        self.step(Expect::Exact(packet.as_bytes().to_vec()), true, &[])
+    }
+
+    fn unexpected(&self, got: &str, expected: &str) -> ! {
+        let common = got
+            .bytes()
+            .zip(expected.bytes())
+            .take_while(|(a, b)| a == b)
+            .count();
+        panic!(
+            "unexpected packet #{}\n  expected: {}\n       got: {}\n            {}^",
+            self.received + 1,
+            expected,
+            got,
+            " ".repeat(common)
+        );
+    }
+
+    fn on_packet(&mut self, payload: Option<&[u8]>) {
+        let got = payload.map_or_else(|| "<Ctrl-C>".to_string(), show);
+        let Some(step) = self.steps.pop_front() else {
+            panic!(
+                "unexpected packet #{} after end of script: {}",
+                self.received + 1,
+                got
+            );
+        };
+
+        let (matched, expected) = match (&step.expect, payload) {
+            (Expect::Exact(e), Some(p)) => (p == e.as_slice(), show(e)),
+            (Expect::Prefix(e), Some(p)) => (p.starts_with(e), format!("{}...", show(e))),
+            (Expect::Interrupt, None) => (true, String::new()),
+            (Expect::Interrupt, Some(_)) => (false, "<Ctrl-C>".to_string()),
+            (Expect::Exact(e), None) => (false, show(e)),
+            (Expect::Prefix(e), None) => (false, format!("{}...", show(e))),
+        };
+        if !matched {
+            self.unexpected(&got, &expected);
+        }
+        self.received += 1;
+
+        if payload.is_some() {
+            self.pending.push_back(if step.nack { b'-' } else { b'+' });
+        }
+        for r in &step.replies {
+            self.pending.extend(MockTransport::rsp_packet(r));
+        }
+    }
+}
+
+impl GdbTransport for ScriptedTransport {
+    fn connect(&mut self) -> io::Result<()> {
+        self.connected = true;
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.connected = false;
+        Ok(())
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        match data {
+            // 客户端对回复的 ACK
+            b"+" | b"-" => {}
+            [0x03] => self.on_packet(None),
+            [b'$', body @ ..] => {
+                let end = body.iter().rposition(|b| *b == b'#').unwrap_or(body.len());
+                self.on_packet(Some(&body[..end]));
+            }
+            _ => panic!("malformed data from client: {}", show(data)),
+        }
+        Ok(())
+    }
+
+    fn recv_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        if self.pending.len() < buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "client is waiting for data the script does not provide",
+            ));
+        }
+        for b in buf.iter_mut() {
+            *b = self.pending.pop_front().unwrap_or_default();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ScriptedTransport {
+    fn drop(&mut self) {
+        if !std::thread::panicking() && !self.steps.is_empty() {
+            panic!(
+                "script not finished, {} step(s) left, next: {:?}",
+                self.steps.len(),
+                self.steps[0].expect
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gdb_client::GdbClient;
+
+    #[test]
+    fn test_flash_transcript() {
+        let script = ScriptedTransport::new()
+            .expect("vFlashErase:8000000,400", &["OK"])
+            .expect_prefix("vFlashWrite:8000000:", &["OK"])
+            .expect("vFlashDone", &["OK"])
+            .expect("m8000000,4", &["01020304"]);
+
+        let mut client = GdbClient::new(script);
+        client.connect().unwrap();
+
+        client.flash_erase(0x0800_0000, 0x400).unwrap();
+        client.flash_write(0x0800_0000, &[1, 2, 3, 4], 256).unwrap();
+        client.flash_done().unwrap();
+        assert_eq!(client.read_u32(0x0800_0000).unwrap(), 0x0403_0201);
+    }
+
+    #[test]
+    fn test_debug_transcript() {
+        let script = ScriptedTransport::new()
+            .expect("Z1,8000100,2", &["OK"])
+            .expect("c", &[])
+            .expect_interrupt(&["T02"])
+            .expect("z1,8000100,2", &["OK"])
+            .nack("D");
+
+        let mut client = GdbClient::new(script);
+        client.connect().unwrap();
+
+        client.insert_breakpoint(1, 0x0800_0100, 2).unwrap();
+        client.resume().unwrap();
+        client.interrupt().unwrap();
+        client.wait_stop_reply().unwrap();
+        client.remove_breakpoint(1, 0x0800_0100, 2).unwrap();
+        assert!(client.detach().is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected packet #1\n  expected: vFlashErase:8000000,400")]
+    fn test_mismatch_panics() {
+        let script = ScriptedTransport::new().expect("vFlashErase:8000000,400", &["OK"]);
+        let mut client = GdbClient::new(script);
+        client.connect().unwrap();
+
+        let _ = client.flash_erase(0x0800_0000, 0x800);
+    }
+
+    #[test]
+    #[should_panic(expected = "script not finished")]
+    fn test_unfinished_script_panics() {
+        let script = ScriptedTransport::new().expect("vFlashDone", &["OK"]);
+        drop(GdbClient::new(script));
+    }
+}
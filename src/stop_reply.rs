@@ -0,0 +1,254 @@
+use crate::error::{Error, OpKind, Result};
+
+/// ARM Cortex-M 的 PC 寄存器编号
+pub const REG_SP: u32 = 13;
+pub const REG_PC: u32 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,  // watch
+    Read,   // rwatch
+    Access, // awatch
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    Signal,
+    Watchpoint { kind: WatchKind, addr: u32 },
+    SwBreak,
+    HwBreak,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StopEvent {
+    pub signal: u8,
+    pub thread: Option<String>,
+    /// expedited 寄存器：(编号, 目标字节序的原始字节)
+    pub registers: Vec<(u32, Vec<u8>)>,
+    pub reason: StopReason,
+}
+
+impl StopEvent {
+    /// 按小端解码一个 expedited 寄存器
+    pub fn register(&self, regnum: u32) -> Option<u32> {
+        let (_, bytes) = self.registers.iter().find(|(n, _)| *n == regnum)?;
+        let mut v = [0u8; 4];
+        for (d, s) in v.iter_mut().zip(bytes.iter()) {
+            *d = *s;
+        }
+        Some(u32::from_le_bytes(v))
+    }
+
+    pub fn pc(&self) -> Option<u32> {
+        self.register(REG_PC)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReply {
+    /// `S` / `T`
+    Stopped(StopEvent),
+    /// `W`：程序退出，附带退出码
+    Exited(u8),
+    /// `X`：程序被信号终止
+    Terminated(u8),
+    /// `O`：控制台输出（已解码）
+    Output(Vec<u8>),
+}
+
+fn invalid(op: &str, msg: String) -> Error {
+    Error::Malformed {
+        op: op.into(),
+        kind: OpKind::Other,
+        reply: msg,
+    }
+}
+
+fn parse_hex_u8(s: &[u8]) -> Result<u8> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| u8::from_str_radix(s, 16).ok())
+        .ok_or_else(|| {
+            invalid(
+                "hex",
+                format!("bad hex byte: {:?}", String::from_utf8_lossy(s)),
+            )
+        })
+}
+
+fn parse_hex_u32(s: &[u8]) -> Result<u32> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| u64::from_str_radix(s, 16).ok())
+        .map(|v| v as u32)
+        .ok_or_else(|| {
+            invalid(
+                "hex",
+                format!("bad hex value: {:?}", String::from_utf8_lossy(s)),
+            )
+        })
+}
+
+pub fn hex_decode(s: &[u8]) -> Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return Err(invalid(
+            "hex",
+            format!("odd hex length: {:?}", String::from_utf8_lossy(s)),
+        ));
+    }
+    s.chunks(2).map(parse_hex_u8).collect()
+}
+
+/// 解析 `S`/`T`/`W`/`X`/`O` 回复
+pub fn parse_stop_reply(payload: &[u8]) -> Result<StopReply> {
+    let Some((&kind, rest)) = payload.split_first() else {
+        return Err(invalid("stop", "empty stop reply".into()));
+    };
+
+    match kind {
+        b'S' => Ok(StopReply::Stopped(StopEvent {
+            signal: parse_hex_u8(rest.get(..2).unwrap_or(rest))?,
+            thread: None,
+            registers: Vec::new(),
+            reason: StopReason::Signal,
+        })),
+        b'T' => parse_t_reply(rest).map(StopReply::Stopped),
+        // W/X 可能带有 ";process:pid"
+        b'W' => Ok(StopReply::Exited(parse_hex_u8(
+            rest.split(|&b| b == b';').next().unwrap_or(rest),
+        )?)),
+        b'X' => Ok(StopReply::Terminated(parse_hex_u8(
+            rest.split(|&b| b == b';').next().unwrap_or(rest),
+        )?)),
+        b'O' => hex_decode(rest).map(StopReply::Output),
+        _ => Err(invalid(
+            "stop",
+            format!("not a stop reply: {}", String::from_utf8_lossy(payload)),
+        )),
+    }
+}
+
+fn parse_t_reply(rest: &[u8]) -> Result<StopEvent> {
+    if rest.len() < 2 {
+        return Err(invalid("stop", "truncated T reply".into()));
+    }
+
+    let mut ev = StopEvent {
+        signal: parse_hex_u8(&rest[..2])?,
+        thread: None,
+        registers: Vec::new(),
+        reason: StopReason::Signal,
+    };
+
+    for pair in rest[2..].split(|&b| b == b';').filter(|p| !p.is_empty()) {
+        let mut it = pair.splitn(2, |&b| b == b':');
+        let key = it.next().unwrap_or_default();
+        let value = it.next().unwrap_or_default();
+
+        match key {
+            b"thread" => ev.thread = Some(String::from_utf8_lossy(value).into_owned()),
+            b"watch" | b"rwatch" | b"awatch" => {
+                let kind = match key {
+                    b"watch" => WatchKind::Write,
+                    b"rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                ev.reason = StopReason::Watchpoint {
+                    kind,
+                    addr: parse_hex_u32(value)?,
+                };
+            }
+            b"swbreak" => ev.reason = StopReason::SwBreak,
+            b"hwbreak" => ev.reason = StopReason::HwBreak,
+            _ => {
+                // 寄存器编号是十六进制数，其余未知的 key 按协议要求忽略
+                if let Ok(regnum) = parse_hex_u32(key) {
+                    ev.registers.push((regnum, hex_decode(value)?));
+                }
+            }
+        }
+    }
+
+    Ok(ev)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_s_reply() {
+        let reply = parse_stop_reply(b"S05").unwrap();
+        match reply {
+            StopReply::Stopped(ev) => {
+                assert_eq!(ev.signal, 5);
+                assert_eq!(ev.reason, StopReason::Signal);
+                assert_eq!(ev.pc(), None);
+            }
+            _ => panic!("unexpected {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_parse_t_reply_with_pc_and_thread() {
+        let reply = parse_stop_reply(b"T05thread:1;0f:34120008;0d:00100020;swbreak:;").unwrap();
+        let StopReply::Stopped(ev) = reply else {
+            panic!("unexpected {:?}", reply);
+        };
+
+        assert_eq!(ev.signal, 5);
+        assert_eq!(ev.thread.as_deref(), Some("1"));
+        assert_eq!(ev.pc(), Some(0x0800_1234));
+        assert_eq!(ev.register(13), Some(0x2000_1000));
+        assert_eq!(ev.reason, StopReason::SwBreak);
+    }
+
+    #[test]
+    fn test_parse_watchpoints() {
+        for (pkt, kind) in [
+            (&b"T05watch:20000010;"[..], WatchKind::Write),
+            (&b"T05rwatch:20000010;"[..], WatchKind::Read),
+            (&b"T05awatch:20000010;"[..], WatchKind::Access),
+        ] {
+            let StopReply::Stopped(ev) = parse_stop_reply(pkt).unwrap() else {
+                panic!("not stopped");
+            };
+            assert_eq!(
+                ev.reason,
+                StopReason::Watchpoint {
+                    kind,
+                    addr: 0x2000_0010
+                }
+            );
+        }
+
+        let StopReply::Stopped(ev) = parse_stop_reply(b"T05hwbreak:;").unwrap() else {
+            panic!("not stopped");
+        };
+        assert_eq!(ev.reason, StopReason::HwBreak);
+    }
+
+    #[test]
+    fn test_parse_exit_and_output() {
+        assert_eq!(parse_stop_reply(b"W00").unwrap(), StopReply::Exited(0));
+        assert_eq!(
+            parse_stop_reply(b"W01;process:1").unwrap(),
+            StopReply::Exited(1)
+        );
+        assert_eq!(
+            parse_stop_reply(b"X0b").unwrap(),
+            StopReply::Terminated(0x0b)
+        );
+        assert_eq!(
+            parse_stop_reply(b"O68690a").unwrap(),
+            StopReply::Output(b"hi\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse_stop_reply(b"").is_err());
+        assert!(parse_stop_reply(b"OK").is_err());
+        assert!(parse_stop_reply(b"Tzz").is_err());
+    }
+}
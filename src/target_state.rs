@@ -0,0 +1,120 @@
+use crate::stop_reply::StopReply;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetState {
+    Disconnected,
+    Halted,
+    Running,
+    Exited,
+    /// 已发出复位，停止状态尚未确认
+    Reset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetEvent {
+    /// 收到 S/T 停止回复
+    Stopped,
+    /// 收到 W/X 回复
+    Exited,
+    Resumed,
+    ResetIssued,
+    Disconnected,
+}
+
+impl TargetEvent {
+    /// `O` 输出不改变状态，返回 None
+    pub fn from_stop_reply(reply: &StopReply) -> Option<Self> {
+        match reply {
+            StopReply::Stopped(_) => Some(TargetEvent::Stopped),
+            StopReply::Exited(_) | StopReply::Terminated(_) => Some(TargetEvent::Exited),
+            StopReply::Output(_) => None,
+        }
+    }
+}
+
+impl TargetState {
+    /// 状态转换表，非法转换返回 None
+    pub fn next(self, event: TargetEvent) -> Option<TargetState> {
+        use TargetEvent as E;
+        use TargetState as S;
+
+        match (self, event) {
+            (_, E::Disconnected) => Some(S::Disconnected),
+
+            // 连接后通过 `?` 得到初始状态
+            (S::Disconnected, E::Stopped) => Some(S::Halted),
+            (S::Disconnected, E::Exited) => Some(S::Exited),
+
+            (S::Halted, E::Stopped) => Some(S::Halted),
+            (S::Halted, E::Resumed) => Some(S::Running),
+            (S::Halted, E::ResetIssued) => Some(S::Reset),
+
+            (S::Running, E::Stopped) => Some(S::Halted),
+            (S::Running, E::Exited) => Some(S::Exited),
+            (S::Running, E::ResetIssued) => Some(S::Reset),
+
+            (S::Reset, E::Stopped) => Some(S::Halted),
+            (S::Reset, E::Resumed) => Some(S::Running),
+            (S::Reset, E::ResetIssued) => Some(S::Reset),
+
+            (S::Exited, E::Stopped) => Some(S::Halted),
+            (S::Exited, E::ResetIssued) => Some(S::Reset),
+
+            _ => None,
+        }
+    }
+
+    pub fn is_connected(self) -> bool {
+        self != TargetState::Disconnected
+    }
+
+    /// 内存 / 寄存器访问、断点修改只在停止时进行
+    pub fn is_halted(self) -> bool {
+        self == TargetState::Halted
+    }
+
+    pub fn can_resume(self) -> bool {
+        matches!(self, TargetState::Halted | TargetState::Reset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_halt_cycle() {
+        let s = TargetState::Disconnected;
+        let s = s.next(TargetEvent::Stopped).unwrap();
+        assert_eq!(s, TargetState::Halted);
+
+        let s = s.next(TargetEvent::Resumed).unwrap();
+        assert_eq!(s, TargetState::Running);
+        assert!(!s.is_halted());
+
+        let s = s.next(TargetEvent::Stopped).unwrap();
+        assert_eq!(s, TargetState::Halted);
+
+        let s = s.next(TargetEvent::Disconnected).unwrap();
+        assert_eq!(s, TargetState::Disconnected);
+    }
+
+    #[test]
+    fn test_reset_and_exit() {
+        let s = TargetState::Running.next(TargetEvent::ResetIssued).unwrap();
+        assert_eq!(s, TargetState::Reset);
+        assert!(s.can_resume());
+        assert_eq!(s.next(TargetEvent::Stopped), Some(TargetState::Halted));
+
+        let s = TargetState::Running.next(TargetEvent::Exited).unwrap();
+        assert_eq!(s, TargetState::Exited);
+        assert!(!s.can_resume());
+    }
+
+    #[test]
+    fn test_invalid_transitions() {
+        assert_eq!(TargetState::Disconnected.next(TargetEvent::Resumed), None);
+        assert_eq!(TargetState::Running.next(TargetEvent::Resumed), None);
+        assert_eq!(TargetState::Exited.next(TargetEvent::Resumed), None);
+    }
+}